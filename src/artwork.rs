@@ -0,0 +1,117 @@
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::{config::Config, media::{Sidecar, Contents}, tmdb::{self, Kind}};
+
+const POSTER_SIZES: [&str; 7] = ["w92", "w154", "w185", "w342", "w500", "w780", "original"];
+const FANART_SIZES: [&str; 4] = ["w300", "w780", "w1280", "original"];
+
+// Artwork downloaded from TMDB along with the media
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Artwork {
+    pub enabled: bool,
+    // Preferred languages of posters (ISO-639-1), in order, images without text come after them
+    pub languages: Vec<String>,
+    // TMDB image sizes, e.g. "w780" or "original"
+    pub poster_size: String,
+    pub fanart_size: String,
+}
+
+impl Default for Artwork {
+    fn default() -> Artwork {
+        Artwork {
+            enabled: false,
+            languages: vec!["en".to_string()],
+            poster_size: "original".to_string(),
+            fanart_size: "original".to_string(),
+        }
+    }
+}
+
+impl Artwork {
+    // Check the image sizes are ones TMDB offers
+    pub fn validate(&self) -> Result<(), String> {
+        if !POSTER_SIZES.contains(&self.poster_size.as_str()) {
+            return Err(format!("Invalid poster_size {:?}, expected one of {}", self.poster_size, POSTER_SIZES.join(", ")));
+        }
+        if !FANART_SIZES.contains(&self.fanart_size.as_str()) {
+            return Err(format!("Invalid fanart_size {:?}, expected one of {}", self.fanart_size, FANART_SIZES.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+// Images of a movie, show or season on TMDB
+#[derive(Deserialize, Debug)]
+struct Images {
+    #[serde(default)]
+    posters: Vec<Image>,
+    #[serde(default)]
+    backdrops: Vec<Image>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Image {
+    file_path: String,
+    iso_639_1: Option<String>,
+}
+
+// Poster and fanart of a movie or show in its folder, leaving out what exists already
+pub fn item(kind: Kind, id: i32, dir: &Path, cfg: &Config) -> Vec<Sidecar> {
+    let poster = dir.join("poster.jpg");
+    let fanart = dir.join("fanart.jpg");
+    if !cfg.artwork.enabled || (poster.exists() && fanart.exists()) {
+        return Vec::new();
+    }
+    let Some(images) = images(&format!("{}/{}/images", kind.endpoint(), id), cfg) else { return Vec::new(); };
+
+    let mut sidecars = Vec::new();
+    if let (false, Some(image)) = (poster.exists(), pick(&images.posters, &cfg.artwork.languages, false)) {
+        sidecars.push(Sidecar { path: poster, contents: Contents::Download(url(&cfg.artwork.poster_size, image, cfg)), item: true });
+    }
+    if let (false, Some(image)) = (fanart.exists(), pick(&images.backdrops, &cfg.artwork.languages, true)) {
+        sidecars.push(Sidecar { path: fanart, contents: Contents::Download(url(&cfg.artwork.fanart_size, image, cfg)), item: true });
+    }
+    sidecars
+}
+
+// File name of the poster of a season in the show folder, e.g. "season01-poster.jpg"
+pub fn season_poster(season: i32) -> String {
+    if season == 0 { "season-specials-poster.jpg".to_string() } else { format!("season{:02}-poster.jpg", season) }
+}
+
+// Poster of a season in the show folder
+pub fn season(id: i32, season: i32, dir: &Path, cfg: &Config) -> Vec<Sidecar> {
+    let poster = dir.join(season_poster(season));
+    if !cfg.artwork.enabled || poster.exists() {
+        return Vec::new();
+    }
+    let Some(images) = images(&format!("tv/{}/season/{}/images", id, season), cfg) else { return Vec::new(); };
+
+    match pick(&images.posters, &cfg.artwork.languages, false) {
+        Some(image) => vec![Sidecar { path: poster, contents: Contents::Download(url(&cfg.artwork.poster_size, image, cfg)), item: true }],
+        None => Vec::new(),
+    }
+}
+
+fn images(endpoint: &str, cfg: &Config) -> Option<Images> {
+    let languages: Vec<&str> = cfg.artwork.languages.iter().map(String::as_str).chain(["null"]).collect();
+    tmdb::get(cfg, &format!("{}?include_image_language={}", endpoint, languages.join(",")))
+}
+
+// Pick the best voted image in the preferred languages, or without text (first, for fanart), or any at all
+fn pick<'a>(images: &'a [Image], languages: &[String], textless_first: bool) -> Option<&'a Image> {
+    let textless = || images.iter().find(|i| i.iso_639_1.is_none());
+    let preferred = || languages.iter().find_map(|l| images.iter().find(|i| i.iso_639_1.as_ref() == Some(l)));
+    if textless_first {
+        textless().or_else(preferred).or(images.first())
+    } else {
+        preferred().or_else(textless).or(images.first())
+    }
+}
+
+fn url(size: &str, image: &Image, cfg: &Config) -> String {
+    format!("{}/{}{}", cfg.tmdb_image_url.trim_end_matches('/'), size, image.file_path)
+}
@@ -0,0 +1,86 @@
+use std::{fs, io::ErrorKind, path::PathBuf, time::{Duration, UNIX_EPOCH}};
+
+use log::{warn, trace};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{config::Config, media::timestamp};
+
+// Cached TMDB response, one file per URL
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Cached {
+    pub timestamp: u64,
+    pub url: String,
+    pub response: Value,
+}
+
+impl Cached {
+    // Whether the response is younger than the TTL from the config
+    pub fn fresh(&self, cfg: &Config) -> bool {
+        timestamp().saturating_sub(self.timestamp) < cfg.cache_ttl_days * 24 * 60 * 60
+    }
+
+    // Time the response has been cached at, for log messages
+    pub fn time(&self) -> String {
+        humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(self.timestamp)).to_string()
+    }
+}
+
+// Directory holding the cached responses
+pub fn dir(cfg: &Config) -> PathBuf {
+    cfg.data_dir.join("cache")
+}
+
+// Full URL of an endpoint, so responses of a stand-in server (see `tmdb_url`) and of TMDB do not get mixed up
+fn url(cfg: &Config, endpoint: &str) -> String {
+    format!("{}/{}", cfg.tmdb_url.trim_end_matches('/'), endpoint)
+}
+
+fn path(cfg: &Config, url: &str) -> PathBuf {
+    dir(cfg).join(format!("{:016x}.json", xxh3_64(url.as_bytes())))
+}
+
+// Cached response for an endpoint, no matter how old, None if there is none or the cache is disabled for this run
+pub fn get(cfg: &Config, endpoint: &str) -> Option<Cached> {
+    if cfg.no_cache {
+        return None;
+    }
+    let url = url(cfg, endpoint);
+    let f = fs::read_to_string(path(cfg, &url)).ok()?;
+    // Different URLs could end up with the same hash
+    serde_json::from_str::<Cached>(&f).ok().filter(|c| c.url == url)
+}
+
+// Store the response for an endpoint
+pub fn put(cfg: &Config, endpoint: &str, response: &Value) {
+    if cfg.no_cache {
+        return;
+    }
+    let url = url(cfg, endpoint);
+    let cached = Cached { timestamp: timestamp(), url: url.clone(), response: response.clone() };
+    let written = fs::create_dir_all(dir(cfg))
+        .and_then(|_| serde_json::to_string(&cached).map_err(std::io::Error::other))
+        .and_then(|s| fs::write(path(cfg, &url), s));
+    match written {
+        Ok(()) => trace!("Cached TMDB response for {:#?}", endpoint),
+        Err(e) => warn!("Can not cache TMDB response for {:#?}: {:#?}", endpoint, e),
+    }
+}
+
+// Remove all cached responses, returning how many there were
+pub fn clear(cfg: &Config) -> std::io::Result<usize> {
+    let entries = match fs::read_dir(dir(cfg)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        if entry.path().extension().is_some_and(|e| e == "json") {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
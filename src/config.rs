@@ -1,17 +1,143 @@
-use std::{fs, error::Error, path::PathBuf, io::ErrorKind, str::FromStr};
+use std::{fs, error::Error, path::{Path, PathBuf}, io::ErrorKind, str::FromStr};
 use inquire::{Text, CustomUserError, Autocomplete, autocompletion::Replacement};
 use log::{warn, info, error};
 use serde::{Serialize, Deserialize};
 
+use crate::{fixtures::Fixtures, conflict::Policy, transfer::{LinkFallback, TransferLimit}, permissions::Permissions, naming::{Naming, Profile, Templates}, artwork::Artwork};
+
 // Struct to hold the config values
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Config {
     pub tmdb_key: String,
     pub plex_library: PathBuf,
+
+    // Base URLs of the TMDB API and image server, e.g. to point them at a local stand-in server
+    #[serde(default = "default_tmdb_url")]
+    pub tmdb_url: String,
+    #[serde(default = "default_tmdb_image_url")]
+    pub tmdb_image_url: String,
+
+    // Minimum score (0.0 to 1.0) the best TMDB result needs to be picked automatically in non-interactive mode
+    #[serde(default = "default_match_threshold")]
+    pub match_threshold: f64,
+
+    // Minimum lead the best TMDB result needs over the runner-up to be picked automatically, so near-ties go to review
+    #[serde(default = "default_match_margin")]
+    pub match_margin: f64,
+
+    // What to do when a file would end up where there already is one
+    #[serde(default)]
+    pub conflict_policy: Policy,
+
+    // What to do when files can not be hard- or reflinked into the library
+    #[serde(default)]
+    pub link_fallback: LinkFallback,
+
+    // Verify copies by comparing checksums of source and destination, sources of moves only get deleted after that
+    #[serde(default)]
+    pub verify: bool,
+
+    // Number of files transferred at the same time
+    #[serde(default = "default_transfer_workers")]
+    pub transfer_workers: usize,
+
+    // Lower limits for transfers from and/or to certain devices (e.g. 1 for copies within the same SMB share)
+    #[serde(default)]
+    pub transfer_limits: Vec<TransferLimit>,
+
+    // Media server the library is named for
+    #[serde(default)]
+    pub profile: Profile,
+
+    // Templates for the paths of files in the library, overriding the ones of the profile
+    #[serde(default)]
+    pub naming: Naming,
+
+    // Write Kodi/Jellyfin compatible NFO files along with movies and episodes
+    #[serde(default)]
+    pub nfo: bool,
+
+    // Posters and fanart downloaded from TMDB along with movies and shows
+    #[serde(default)]
+    pub artwork: Artwork,
+
+    // Owner, mode and timestamps of files and directories created in the library
+    #[serde(default)]
+    pub permissions: Permissions,
+
+    // Days TMDB responses are cached for before asking again, older ones are only used if TMDB can not be reached
+    #[serde(default = "default_cache_ttl_days")]
+    pub cache_ttl_days: u64,
+
+    // Runtime options, set from the command line and never written to the config file
+    #[serde(skip)]
+    pub non_interactive: bool,
+    #[serde(skip)]
+    pub progress: bool,
+    #[serde(skip)]
+    pub no_cache: bool,
+    #[serde(skip)]
+    pub ignore_decisions: bool,
+    #[serde(skip)]
+    pub fixtures: Fixtures,
+
+    // Directory holding the config file, used for everything else the tool stores on disk
+    #[serde(skip)]
+    pub data_dir: PathBuf,
+
+    // Templates actually used, set when loading
+    #[serde(skip)]
+    pub templates: Templates,
+}
+
+fn default_tmdb_url() -> String {
+    "https://api.themoviedb.org/3".to_string()
+}
+
+fn default_tmdb_image_url() -> String {
+    "https://image.tmdb.org/t/p".to_string()
+}
+
+fn default_match_threshold() -> f64 {
+    0.8
+}
+
+fn default_match_margin() -> f64 {
+    0.1
+}
+
+fn default_transfer_workers() -> usize {
+    1
+}
+
+fn default_cache_ttl_days() -> u64 {
+    7
 }
 
 // Load config, or trigger first run wizard
 pub fn load(path: &PathBuf, first: bool) -> Result<Config, Box<dyn Error>> {
+    let mut cfg = read_or_create(path, first)?;
+    cfg.templates = cfg.naming.templates(cfg.profile);
+    cfg.templates.validate()?;
+    cfg.artwork.validate()?;
+    cfg.permissions.validate()?;
+    cfg.data_dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    Ok(cfg)
+}
+
+// Config for tests, with the library and data in a fresh directory named after the test
+#[cfg(test)]
+pub fn scratch(name: &str) -> Config {
+    let dir = std::env::temp_dir().join(format!("plex-media-ingest-{}-{}", name, std::process::id()));
+    _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("library")).unwrap();
+    let mut cfg: Config = serde_json::from_value(serde_json::json!({ "tmdb_key": "", "plex_library": dir.join("library") })).unwrap();
+    cfg.templates = cfg.naming.templates(cfg.profile);
+    cfg.data_dir = dir;
+    cfg
+}
+
+fn read_or_create(path: &PathBuf, first: bool) -> Result<Config, Box<dyn Error>> {
     if first {
         // If first run wizard should be re-run don't bother with the existing config, run wizard and save it
         info!("Running first run wizard...");
@@ -48,7 +174,7 @@ pub fn first_run() -> Result<Config, Box<dyn Error>> {
 
     let tmdb_key = match tmdb_key {
         Ok(tmdb_key) => tmdb_key,
-        Err(e) => panic!("Error retrieving TMDB key from inquire: {}", e.to_string())
+        Err(e) => panic!("Error retrieving TMDB key from inquire: {}", e)
     };
 
     let plex_library = Text::new("Enter your Plex Media Library path:")
@@ -58,15 +184,40 @@ pub fn first_run() -> Result<Config, Box<dyn Error>> {
 
     let plex_library = match plex_library {
         Ok(plex_library) => plex_library,
-        Err(e) => panic!("Error retrieving Plex Library from inquire: {}", e.to_string())
+        Err(e) => panic!("Error retrieving Plex Library from inquire: {}", e)
     };
 
     let plex_library = match PathBuf::from_str(&plex_library) {
         Ok(plex_library) => plex_library,
-        Err(e) => panic!("Path is not valid: {}", e.to_string())
+        Err(e) => panic!("Path is not valid: {}", e)
     };
 
-    Ok(Config { tmdb_key: tmdb_key, plex_library:  plex_library})
+    Ok(Config {
+        tmdb_key,
+        plex_library,
+        tmdb_url: default_tmdb_url(),
+        tmdb_image_url: default_tmdb_image_url(),
+        match_threshold: default_match_threshold(),
+        match_margin: default_match_margin(),
+        conflict_policy: Policy::default(),
+        link_fallback: LinkFallback::default(),
+        verify: false,
+        transfer_workers: default_transfer_workers(),
+        transfer_limits: Vec::new(),
+        profile: Profile::default(),
+        naming: Naming::default(),
+        nfo: false,
+        artwork: Artwork::default(),
+        permissions: Permissions::default(),
+        cache_ttl_days: default_cache_ttl_days(),
+        non_interactive: false,
+        progress: false,
+        no_cache: false,
+        ignore_decisions: false,
+        fixtures: Fixtures::Off,
+        data_dir: PathBuf::new(),
+        templates: Templates::default(),
+    })
 }
 
 // Serialise and save config object to disk
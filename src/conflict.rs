@@ -0,0 +1,324 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use clap::ValueEnum;
+use log::{info, warn, debug};
+use serde::{Serialize, Deserialize};
+
+use crate::{media::{self, Move, Sidecar}, transfer};
+
+// What to do when a file would end up where there already is one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    // Leave the existing file alone and do not transfer the new one
+    #[default]
+    Skip,
+    // Replace the existing file
+    Overwrite,
+    // Keep whichever file is bigger
+    KeepLarger,
+    // Keep whichever file has the higher resolution, or is bigger if that can not be told from the names
+    KeepHigherQuality,
+    // Transfer the new file with a numbered suffix, e.g. "Movie (2019) {tmdb-1} (1).mkv"
+    Rename,
+}
+
+// Resolve collisions of moves with each other and with files already in the library, names differing only in case collide as well (SMB mounts)
+pub fn resolve(moves: Vec<Move>, policy: Policy) -> Vec<Move> {
+    let items: Vec<Sidecar> = moves.iter().flat_map(|m| m.sidecars.iter().filter(|s| s.item)).cloned().collect();
+    let mut resolved = resolve_existing(resolve_batch(moves, policy), policy);
+    hand_over(items, &mut resolved);
+    resolved
+}
+
+// Files of a movie, show or season are planned along with one of its files, hand them over to another file going into the same folder
+// if that one has been dropped (e.g. new episodes of a show whose first episode is in the library already)
+fn hand_over(items: Vec<Sidecar>, moves: &mut [Move]) {
+    for sidecar in items {
+        if moves.iter().any(|m| m.sidecars.iter().any(|s| s.path == sidecar.path)) {
+            continue;
+        }
+        let dir = sidecar.path.parent().unwrap_or(Path::new(""));
+        match moves.iter_mut().find(|m| m.to.starts_with(dir)) {
+            Some(m) => m.sidecars.push(sidecar),
+            None => debug!("Nothing goes to {:#?} anymore, not writing {:#?}", dir, sidecar.path),
+        }
+    }
+}
+
+// Resolve collisions of moves with files already in the library
+fn resolve_existing(moves: Vec<Move>, policy: Policy) -> Vec<Move> {
+    let mut taken: Vec<String> = moves.iter().map(|m| key(&m.to)).collect();
+    let mut resolved: Vec<Move> = Vec::new();
+
+    for mut move_file in moves {
+        // Renaming a file in case only (e.g. when reorganizing) finds the file itself
+        let Some(existing) = find_existing(&move_file.to).filter(|e| !is_source(e, &move_file.from)) else {
+            resolved.push(move_file);
+            continue;
+        };
+        if transfer::is_dangling(&existing) {
+            // Dangling links get replaced when transferring
+            resolved.push(move_file);
+            continue;
+        }
+        if is_same_file(&move_file.from, &existing) {
+            info!("{:#?} is already in place, skipping", existing);
+            continue;
+        }
+
+        let replace = match policy {
+            Policy::Skip => false,
+            Policy::Overwrite => true,
+            Policy::KeepLarger => size(&move_file.from) > size(&existing),
+            Policy::KeepHigherQuality => is_better(&move_file.from, &existing),
+            Policy::Rename => {
+                move_file.to = free_name(&move_file.to, &taken);
+                warn!("{:#?} already exists, renaming new file to {:#?}", existing, move_file.to);
+                taken.push(key(&move_file.to));
+                resolved.push(move_file);
+                continue;
+            }
+        };
+
+        if replace {
+            warn!("{:#?} already exists and will be replaced by {:#?}", existing, move_file.from);
+            // Replace the existing file under its own name, otherwise there would be two files differing in case only
+            move_file.to = existing;
+            resolved.push(move_file);
+        } else {
+            warn!("{:#?} already exists, skipping {:#?}", existing, move_file.from);
+        }
+    }
+    resolved
+}
+
+// Resolve moves within the same batch targeting the same destination
+fn resolve_batch(moves: Vec<Move>, policy: Policy) -> Vec<Move> {
+    let keys: Vec<String> = moves.iter().map(|m| key(&m.to)).collect();
+    let mut taken = keys.clone();
+    let mut resolved: Vec<Move> = Vec::new();
+
+    for (i, move_file) in moves.iter().enumerate() {
+        let rivals: Vec<usize> = (0..moves.len()).filter(|j| keys[*j] == keys[i]).collect();
+        if rivals.len() == 1 {
+            resolved.push(move_file.clone());
+            continue;
+        }
+
+        // Index of the move that gets the destination
+        let winner = match policy {
+            Policy::Skip | Policy::Rename => rivals[0],
+            Policy::Overwrite => rivals[rivals.len() - 1],
+            Policy::KeepLarger => *rivals.iter().reduce(|a, b| if size(&moves[*b].from) > size(&moves[*a].from) { b } else { a }).unwrap(),
+            Policy::KeepHigherQuality => *rivals.iter().reduce(|a, b| if is_better(&moves[*b].from, &moves[*a].from) { b } else { a }).unwrap(),
+        };
+
+        if winner == i {
+            resolved.push(move_file.clone());
+        } else if policy == Policy::Rename {
+            let mut renamed = move_file.clone();
+            renamed.to = free_name(&move_file.to, &taken);
+            warn!("{:#?} and {:#?} both go to {:#?}, renaming the latter to {:#?}", moves[winner].from, move_file.from, move_file.to, renamed.to);
+            taken.push(key(&renamed.to));
+            resolved.push(renamed);
+        } else {
+            warn!("{:#?} and {:#?} both go to {:#?}, skipping {:#?}", moves[winner].from, move_file.from, move_file.to, move_file.from);
+        }
+    }
+    resolved
+}
+
+// Case-insensitive key of a path, for comparing destinations
+fn key(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+// Find a file at the given path, or one whose name only differs in case
+fn find_existing(path: &Path) -> Option<PathBuf> {
+    if fs::symlink_metadata(path).is_ok() {
+        return Some(path.to_path_buf());
+    }
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    fs::read_dir(path.parent()?).ok()?
+        .flatten()
+        .find(|e| e.file_name().to_string_lossy().to_lowercase() == name)
+        .map(|e| e.path())
+}
+
+// Check if a file found for a destination is the source itself under a name differing in case, not another file or a link to it
+pub fn is_source(existing: &Path, from: &Path) -> bool {
+    key(existing) == key(from) && is_same_file(existing, from)
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or_default()
+}
+
+// Check if a file is of higher quality than another, by the resolution in their paths if both have one, by size otherwise
+fn is_better(new: &Path, old: &Path) -> bool {
+    match (media::resolution(&new.to_string_lossy()), media::resolution(&old.to_string_lossy())) {
+        (Some(a), Some(b)) if a != b => a > b,
+        _ => size(new) > size(old),
+    }
+}
+
+// Find a free name by adding a numbered suffix, keeping language and forced tags of subtitles at the end
+fn free_name(path: &Path, taken: &[String]) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let (mut base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) => (base.to_string(), format!(".{}", ext)),
+        None => (name.clone(), String::new()),
+    };
+    let mut tags = String::new();
+    if media::is_subtitle(path) {
+        while let Some((rest, tag)) = base.rsplit_once('.') {
+            if tag.eq_ignore_ascii_case("forced") || (tag.len() == 2 && tag.chars().all(|c| c.is_ascii_alphabetic())) {
+                tags = format!(".{}{}", tag, tags);
+                base = rest.to_string();
+            } else {
+                break;
+            }
+        }
+    }
+
+    let mut n = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{} ({}){}{}", base, n, tags, ext));
+        if find_existing(&candidate).is_none() && !taken.contains(&key(&candidate)) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{config, media::Contents, transfer::{self, Mode}};
+
+    fn file(path: &Path, contents: &str) -> PathBuf {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+        path.to_path_buf()
+    }
+
+    fn sidecar(path: PathBuf, item: bool) -> Sidecar {
+        Sidecar { path, contents: Contents::Text("sidecar".to_string()), item }
+    }
+
+    #[test]
+    fn show_files_go_along_with_new_episodes() {
+        let cfg = config::scratch("show-files");
+        let show = cfg.plex_library.join("TV Shows/Show (2020)");
+        file(&show.join("Season 01/Show - S01E01.mkv"), "old");
+        let e1 = file(&cfg.data_dir.join("src/Show.S01E01.mkv"), "e1");
+        let e2 = file(&cfg.data_dir.join("src/Show.S01E02.mkv"), "e2");
+
+        let moves = vec![
+            Move { from: e1, to: show.join("Season 01/Show - S01E01.mkv"), sidecars: vec![sidecar(show.join("tvshow.nfo"), true), sidecar(show.join("Season 01/Show - S01E01.nfo"), false)] },
+            Move { from: e2, to: show.join("Season 01/Show - S01E02.mkv"), sidecars: vec![sidecar(show.join("Season 01/Show - S01E02.nfo"), false)] },
+        ];
+        let moves = resolve(moves, Policy::Skip);
+        assert_eq!(moves.len(), 1);
+        assert!(moves[0].sidecars.iter().any(|s| s.path == show.join("tvshow.nfo")));
+
+        transfer::execute(moves, Mode::Copy, false, &cfg);
+        assert!(show.join("tvshow.nfo").exists());
+        assert!(show.join("Season 01/Show - S01E02.nfo").exists());
+        assert!(!show.join("Season 01/Show - S01E01.nfo").exists());
+    }
+
+    #[test]
+    fn artwork_goes_along_with_new_episodes() {
+        let mut cfg = config::scratch("artwork");
+        let show = cfg.plex_library.join("TV Shows/Show (2020)");
+        file(&show.join("Season 01/Show - S01E01.mkv"), "old");
+        let e1 = file(&cfg.data_dir.join("src/Show.S01E01.mkv"), "e1");
+        let e2 = file(&cfg.data_dir.join("src/Show.S01E02.mkv"), "e2");
+
+        // Artwork as looked up by the show handler, served from fixtures
+        let fixtures = cfg.data_dir.join("fixtures");
+        let images = serde_json::json!({ "posters": [{ "file_path": "/poster.jpg", "iso_639_1": "en" }], "backdrops": [] });
+        crate::fixtures::save(&fixtures, "tv/1/images?include_image_language=en,null", 200, &images);
+        crate::fixtures::save(&fixtures, "tv/1/season/1/images?include_image_language=en,null", 200, &images);
+        crate::fixtures::save_download(&fixtures, "https://image.tmdb.org/t/p/original/poster.jpg", b"poster");
+        cfg.fixtures = crate::fixtures::Fixtures::Replay(fixtures);
+        cfg.no_cache = true;
+        cfg.artwork.enabled = true;
+        let mut artwork = crate::artwork::item(crate::tmdb::Kind::Tv, 1, &show, &cfg);
+        artwork.append(&mut crate::artwork::season(1, 1, &show, &cfg));
+        assert_eq!(artwork.len(), 2);
+
+        let moves = vec![
+            Move { from: e1, to: show.join("Season 01/Show - S01E01.mkv"), sidecars: artwork },
+            Move { from: e2, to: show.join("Season 01/Show - S01E02.mkv"), sidecars: Vec::new() },
+        ];
+        transfer::execute(resolve(moves, Policy::Skip), Mode::Copy, false, &cfg);
+        assert_eq!(fs::read(show.join("poster.jpg")).unwrap(), b"poster");
+        assert_eq!(fs::read(show.join(crate::artwork::season_poster(1))).unwrap(), b"poster");
+    }
+
+    fn moves(cfg: &config::Config, sizes: &[usize]) -> Vec<Move> {
+        sizes.iter().enumerate().map(|(i, size)| {
+            let from = file(&cfg.data_dir.join(format!("src/{}/Movie.mkv", i)), &"x".repeat(*size));
+            Move { from, to: cfg.plex_library.join("Movies/Movie (2020)/Movie (2020).mkv"), sidecars: Vec::new() }
+        }).collect()
+    }
+
+    #[test]
+    fn same_destination_in_batch() {
+        let cfg = config::scratch("batch");
+        let sizes = [2, 3];
+        let sources = |resolved: Vec<Move>| resolved.iter().map(|m| m.from.parent().unwrap().file_name().unwrap().to_string_lossy().to_string()).collect::<Vec<_>>();
+
+        assert_eq!(sources(resolve_batch(moves(&cfg, &sizes), Policy::Skip)), ["0"]);
+        assert_eq!(sources(resolve_batch(moves(&cfg, &sizes), Policy::Overwrite)), ["1"]);
+        assert_eq!(sources(resolve_batch(moves(&cfg, &sizes), Policy::KeepLarger)), ["1"]);
+        assert_eq!(sources(resolve_batch(moves(&cfg, &[3, 2]), Policy::KeepLarger)), ["0"]);
+
+        // Destinations differing in case only collide as well
+        let mut differing = moves(&cfg, &sizes);
+        differing[1].to = cfg.plex_library.join("Movies/Movie (2020)/movie (2020).mkv");
+        assert_eq!(resolve_batch(differing, Policy::Skip).len(), 1);
+
+        let renamed = resolve_batch(moves(&cfg, &sizes), Policy::Rename);
+        let names: Vec<&Path> = renamed.iter().map(|m| m.to.strip_prefix(&cfg.plex_library).unwrap()).collect();
+        assert_eq!(names, [Path::new("Movies/Movie (2020)/Movie (2020).mkv"), Path::new("Movies/Movie (2020)/Movie (2020) (1).mkv")]);
+    }
+
+    #[test]
+    fn free_names() {
+        let cfg = config::scratch("free-name");
+        let dir = cfg.plex_library.join("Movies/Movie (2020)");
+        file(&dir.join("Movie (2020).mkv"), "old");
+        file(&dir.join("movie (2020) (1).mkv"), "old");
+        let taken = vec![key(&dir.join("Movie (2020) (2).mkv"))];
+        assert_eq!(free_name(&dir.join("Movie (2020).mkv"), &taken), dir.join("Movie (2020) (3).mkv"));
+        assert_eq!(free_name(&dir.join("Movie (2020).en.forced.srt"), &[]), dir.join("Movie (2020) (1).en.forced.srt"));
+        assert_eq!(free_name(&dir.join("Movie (2020)"), &[]), dir.join("Movie (2020) (1)"));
+    }
+
+    #[test]
+    fn case_only_rename() {
+        let cfg = config::scratch("case-rename");
+        let from = file(&cfg.plex_library.join("Movies/movie (2020).mkv"), "movie");
+        let to = cfg.plex_library.join("Movies/Movie (2020).mkv");
+        let resolved = resolve(vec![Move { from: from.clone(), to: to.clone(), sidecars: Vec::new() }], Policy::Skip);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].to, to);
+
+        // Another file differing in case still collides
+        let other = file(&cfg.data_dir.join("src/Movie.mkv"), "other");
+        assert!(resolve(vec![Move { from: other, to, sidecars: Vec::new() }], Policy::Skip).is_empty());
+    }
+}
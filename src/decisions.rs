@@ -0,0 +1,116 @@
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}, time::UNIX_EPOCH};
+
+use log::{error, debug};
+use serde::{Serialize, Deserialize};
+
+use crate::config::Config;
+
+// Answers given for a source file or folder, replayed on later runs
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Decision {
+    // Size and modification time of files, to tell if they changed since, None for folders
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tmdb_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edition: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    pub forced: bool,
+    // File has been ignored or discarded
+    pub skip: bool,
+}
+
+// Location of the decisions store, one JSON object keyed by path
+pub fn path(cfg: &Config) -> PathBuf {
+    cfg.data_dir.join("decisions.json")
+}
+
+fn load(cfg: &Config) -> BTreeMap<PathBuf, Decision> {
+    let Ok(f) = fs::read_to_string(path(cfg)) else { return BTreeMap::new(); };
+    match serde_json::from_str(&f) {
+        Ok(decisions) => decisions,
+        Err(e) => {
+            error!("Can not read decisions from {:#?}, ignoring them: {:#?}", path(cfg), e);
+            BTreeMap::new()
+        }
+    }
+}
+
+fn save(cfg: &Config, decisions: &BTreeMap<PathBuf, Decision>) {
+    // Write to a temporary file first, so an interrupted run can not leave half a store behind
+    let tmp = cfg.data_dir.join(".decisions.json.tmp");
+    let written = fs::create_dir_all(&cfg.data_dir)
+        .and_then(|_| serde_json::to_string_pretty(decisions).map_err(std::io::Error::other))
+        .and_then(|s| fs::write(&tmp, s))
+        .and_then(|_| fs::rename(&tmp, path(cfg)));
+    if let Err(e) = written {
+        error!("Can not write decisions to {:#?}: {:#?}", path(cfg), e);
+    }
+}
+
+// Absolute path of a file or folder, so decisions are found no matter where the tool is run from
+fn key(file: &Path) -> PathBuf {
+    fs::canonicalize(file).unwrap_or(file.to_path_buf())
+}
+
+// Size and modification time of a file, None for folders
+fn stamp(file: &Path) -> (Option<u64>, Option<u64>) {
+    match fs::metadata(file) {
+        Ok(meta) if meta.is_file() => {
+            let modified = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs());
+            (Some(meta.len()), modified)
+        },
+        _ => (None, None),
+    }
+}
+
+// Decision recorded for a file or folder, None if there is none, the file changed since or decisions are ignored for this run
+pub fn get(cfg: &Config, file: &Path) -> Option<Decision> {
+    if cfg.ignore_decisions {
+        return None;
+    }
+    let decision = load(cfg).remove(&key(file))?;
+    if (decision.size, decision.modified) != stamp(file) {
+        debug!("{:#?} changed since the decision for it, asking again", file);
+        return None;
+    }
+    debug!("Replaying decision for {:#?}: {:#?}", file, decision);
+    Some(decision)
+}
+
+// Record an answer for a file or folder, starting over if the file changed since earlier answers
+pub fn record(cfg: &Config, file: &Path, update: impl FnOnce(&mut Decision)) {
+    if cfg.ignore_decisions {
+        return;
+    }
+    let mut decisions = load(cfg);
+    let (size, modified) = stamp(file);
+    let decision = decisions.entry(key(file)).or_default();
+    if (decision.size, decision.modified) != (size, modified) {
+        *decision = Decision { size, modified, ..Decision::default() };
+    }
+    update(decision);
+    save(cfg, &decisions);
+}
+
+// Remove the decisions for a file or folder and everything in it, returning how many there were
+pub fn forget(cfg: &Config, under: &Path) -> usize {
+    let mut decisions = load(cfg);
+    let before = decisions.len();
+    let under = key(under);
+    decisions.retain(|p, _| !p.starts_with(&under));
+    let removed = before - decisions.len();
+    if removed > 0 {
+        save(cfg, &decisions);
+    }
+    removed
+}
@@ -1,4 +1,4 @@
-use std::{path::PathBuf, fs::{self, DirEntry}, error::Error};
+use std::{path::PathBuf, fs::{self, DirEntry}, error::Error, cmp::Reverse};
 
 use log::trace;
 
@@ -12,21 +12,19 @@ pub fn search_path(path: PathBuf, cfg: Config, shows: bool) -> Result<Vec<Move>,
     let mut folders: Vec<DirEntry> = Vec::new();
 
     // Put all files and folders in corresponding vectors
-    for entry in entries {
-        if let Ok(entry) = entry {
-            if let Ok(file_type) = entry.file_type() {
-                if file_type.is_dir() {
-                    folders.push(entry);
-                } else if file_type.is_file() {
-                    files.push(entry);
-                }
+    for entry in entries.flatten() {
+        if let Ok(file_type) = entry.file_type() {
+            if file_type.is_dir() {
+                folders.push(entry);
+            } else if file_type.is_file() {
+                files.push(entry);
             }
         }
     }
 
     // Sort the files and directory vectors by size, so the  main movie file (the biggest usually) is the first
-    folders.sort_by(|a, b| b.metadata().unwrap().len().cmp(&a.metadata().unwrap().len()));
-    files.sort_by(|a, b| b.metadata().unwrap().len().cmp(&a.metadata().unwrap().len()));
+    folders.sort_by_key(|e| Reverse(e.metadata().unwrap().len()));
+    files.sort_by_key(|e| Reverse(e.metadata().unwrap().len()));
     trace!("Sorted Dirs: {:#?}", folders);
     trace!("Sorted Files: {:#?}", files);
 
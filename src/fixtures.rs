@@ -0,0 +1,80 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use log::{warn, trace};
+use sanitise_file_name::sanitise;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use xxhash_rust::xxh3::xxh3_64;
+
+// Recording TMDB responses to fixture files, or serving them back instead of asking TMDB
+#[derive(Clone, Debug, Default)]
+pub enum Fixtures {
+    #[default]
+    Off,
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+// Recorded TMDB response, the body is null for failed requests
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Fixture {
+    pub endpoint: String,
+    pub status: u16,
+    pub response: Value,
+}
+
+// Fixture file for an endpoint, named after it so fixtures can be written by hand, e.g. "movie_27205_external_ids.json"
+pub fn path(dir: &Path, endpoint: &str) -> PathBuf {
+    dir.join(format!("{}.json", name(endpoint)))
+}
+
+// Fixture file for a download, holding the file as is and named after its URL, e.g. "image.tmdb.org_t_p_original_abc.jpg"
+pub fn download_path(dir: &Path, url: &str) -> PathBuf {
+    dir.join(name(url.split_once("://").map_or(url, |(_, rest)| rest)))
+}
+
+fn name(request: &str) -> String {
+    let name = sanitise(&request.replace('/', "_"));
+    // Keep long queries within file name limits
+    if name.len() > 200 {
+        let end = (0..=200).rev().find(|i| name.is_char_boundary(*i)).unwrap_or_default();
+        return format!("{}-{:016x}", &name[..end], xxh3_64(request.as_bytes()));
+    }
+    name
+}
+
+// Fixture recorded for an endpoint
+pub fn load(dir: &Path, endpoint: &str) -> Option<Fixture> {
+    let path = path(dir, endpoint);
+    trace!("Replaying {:#?} from {:#?}", endpoint, path);
+    let f = fs::read_to_string(&path).ok()?;
+    match serde_json::from_str(&f) {
+        Ok(fixture) => Some(fixture),
+        Err(e) => {
+            warn!("Can not read fixture {:#?}: {:#?}", path, e);
+            None
+        }
+    }
+}
+
+// Record the response for an endpoint
+pub fn save(dir: &Path, endpoint: &str, status: u16, response: &Value) {
+    let path = path(dir, endpoint);
+    let fixture = Fixture { endpoint: endpoint.to_string(), status, response: response.clone() };
+    let written = fs::create_dir_all(dir)
+        .and_then(|_| serde_json::to_string_pretty(&fixture).map_err(std::io::Error::other))
+        .and_then(|s| fs::write(&path, s));
+    match written {
+        Ok(()) => trace!("Recorded {:#?} to {:#?}", endpoint, path),
+        Err(e) => warn!("Can not record fixture {:#?}: {:#?}", path, e),
+    }
+}
+
+// Record a download
+pub fn save_download(dir: &Path, url: &str, contents: &[u8]) {
+    let path = download_path(dir, url);
+    match fs::create_dir_all(dir).and_then(|_| fs::write(&path, contents)) {
+        Ok(()) => trace!("Recorded {:#?} to {:#?}", url, path),
+        Err(e) => warn!("Can not record fixture {:#?}: {:#?}", path, e),
+    }
+}
@@ -0,0 +1,244 @@
+use std::{path::{Path, PathBuf}, fs::{self, OpenOptions}, io::{self, Write}, error::Error, time::{SystemTime, UNIX_EPOCH, Duration}};
+
+use indicatif::HumanBytes;
+use log::{info, warn, error};
+use serde::{Serialize, Deserialize};
+use inline_colorization::*;
+
+use crate::{config::Config, media::timestamp, transfer::{self, Mode}, progress::Progress};
+
+// What happened to a journaled move
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Done,
+    Failed(String),
+    Skipped(String),
+    Undone,
+}
+
+// A transfer that ran into an error failed
+impl From<io::Error> for Outcome {
+    fn from(e: io::Error) -> Outcome {
+        Outcome::Failed(e.to_string())
+    }
+}
+
+// Journal entry for every executed move (or copy)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub run: u64,
+    pub timestamp: u64,
+    pub mode: Mode,
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub size: u64,
+    pub outcome: Outcome,
+    // Where the file previously at the destination has been kept, if there was one
+    pub backup: Option<PathBuf>,
+    // Files written along with this one (e.g. NFO files)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sidecars: Vec<PathBuf>,
+}
+
+// Location of the journal, one JSON entry per line
+pub fn path(cfg: &Config) -> PathBuf {
+    cfg.data_dir.join("journal.jsonl")
+}
+
+// Identifier for a new run, milliseconds since the unix epoch
+pub fn new_run() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+// Append an entry to the journal
+pub fn record(cfg: &Config, entry: &Entry) {
+    let line = match serde_json::to_string(entry) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("Can not serialise journal entry: {:#?}", e);
+            return;
+        }
+    };
+
+    let written = fs::create_dir_all(&cfg.data_dir)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(path(cfg)))
+        .and_then(|mut f| writeln!(f, "{}", line));
+    if let Err(e) = written {
+        error!("Can not write to journal, {:#?} can not be undone: {:#?}", entry.to, e);
+    }
+}
+
+// Read the whole journal
+pub fn read(cfg: &Config) -> Vec<Entry> {
+    let Ok(f) = fs::read_to_string(path(cfg)) else { return Vec::new(); };
+    f.lines().filter_map(|l| serde_json::from_str::<Entry>(l).ok()).collect()
+}
+
+// Entries of a run that have been executed and not been undone yet
+fn undoable(entries: &[Entry], run: u64) -> Vec<Entry> {
+    let undone: Vec<&PathBuf> = entries.iter().filter(|e| e.run == run && e.outcome == Outcome::Undone).map(|e| &e.to).collect();
+    entries.iter().filter(|e| e.run == run && e.outcome == Outcome::Done && !undone.contains(&&e.to)).cloned().collect()
+}
+
+// Print all runs in the journal
+pub fn list(cfg: &Config) {
+    let entries = read(cfg);
+    let mut runs: Vec<u64> = Vec::new();
+    for e in &entries {
+        if !runs.contains(&e.run) {
+            runs.push(e.run);
+        }
+    }
+    for run in runs {
+        let first = entries.iter().find(|e| e.run == run).unwrap();
+        let done = entries.iter().filter(|e| e.run == run && e.outcome == Outcome::Done).count();
+        let failed = entries.iter().filter(|e| e.run == run && matches!(e.outcome, Outcome::Failed(_))).count();
+        let open = undoable(&entries, run).len();
+        let time = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(first.timestamp));
+        println!("{style_bold}{}{style_reset} {} ({:?}): {} done, {} failed, {} not undone", run, time, first.mode, done, failed, open);
+    }
+}
+
+// Revert the given run, or the last run that has not been undone yet
+pub fn undo(cfg: &Config, run: Option<u64>, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let entries = read(cfg);
+    let run = match run {
+        Some(run) => run,
+        None => match entries.iter().rev().map(|e| e.run).find(|r| !undoable(&entries, *r).is_empty()) {
+            Some(run) => run,
+            None => {
+                warn!("Nothing to undo");
+                return Ok(());
+            }
+        }
+    };
+
+    let todo = undoable(&entries, run);
+    if todo.is_empty() {
+        warn!("Nothing to undo for run {}", run);
+        return Ok(());
+    }
+
+    info!("Undoing {} file(s) of run {}", todo.len(), run);
+    let progress = Progress::new(todo.iter().map(|e| e.size).sum(), cfg.progress && !dry_run);
+    for entry in todo.into_iter().rev() {
+        match entry.mode {
+            Mode::Move => progress.println(format!("Moving back {style_bold}{color_red}{}{color_reset}{style_reset} -> {style_bold}{color_green}{}{color_reset}{style_reset}", entry.to.display(), entry.from.display())),
+            Mode::Copy | Mode::Reflink => progress.println(format!("Removing copy {style_bold}{color_red}{}{color_reset}{style_reset}", entry.to.display())),
+            Mode::Hardlink | Mode::Symlink | Mode::RelativeSymlink => progress.println(format!("Removing link {style_bold}{color_red}{}{color_reset}{style_reset}", entry.to.display())),
+        }
+        for sidecar in &entry.sidecars {
+            progress.println(format!("Removing {style_bold}{color_red}{}{color_reset}{style_reset}", sidecar.display()));
+        }
+        if dry_run {
+            continue;
+        }
+
+        match revert(&entry, cfg, &progress) {
+            Ok(_) => {
+                for sidecar in &entry.sidecars {
+                    if let Err(e) = fs::remove_file(sidecar) {
+                        warn!("Can not remove {:#?}: {:#?}", sidecar, e);
+                    }
+                    remove_empty_parents(sidecar, &cfg.plex_library);
+                }
+                remove_empty_parents(&entry.to, &cfg.plex_library);
+                record(cfg, &Entry { timestamp: timestamp(), outcome: Outcome::Undone, ..entry });
+            },
+            Err(e) => error!("Can not undo {:#?}: {}", entry.to, e),
+        }
+    }
+    progress.clear();
+    Ok(())
+}
+
+// Files kept for overwritten destinations that are still there, with the time of the run and their size
+pub fn backups(cfg: &Config) -> Vec<(PathBuf, u64, u64)> {
+    read(cfg).into_iter()
+        .filter(|e| e.outcome == Outcome::Done)
+        .filter_map(|e| {
+            let backup = e.backup?;
+            let size = fs::symlink_metadata(&backup).ok()?.len();
+            Some((backup, e.timestamp, size))
+        })
+        .collect()
+}
+
+// Remove the files kept for overwritten destinations, only those of runs older than `older_than` if given
+// Runs can still be undone afterwards, they just can not restore what was overwritten anymore
+pub fn prune(cfg: &Config, older_than: Option<Duration>, dry_run: bool) {
+    let cutoff = older_than.map(|d| timestamp().saturating_sub(d.as_secs()));
+    let backups: Vec<(PathBuf, u64, u64)> = backups(cfg).into_iter().filter(|(_, time, _)| cutoff.is_none_or(|c| *time < c)).collect();
+    if backups.is_empty() {
+        warn!("No backups to prune");
+        return;
+    }
+
+    let mut removed = 0;
+    let mut freed = 0;
+    for (backup, _, size) in backups {
+        println!("Removing backup {style_bold}{color_red}{}{color_reset}{style_reset}", backup.display());
+        if dry_run {
+            continue;
+        }
+        match fs::remove_file(&backup) {
+            Ok(()) => {
+                removed += 1;
+                freed += size;
+            },
+            Err(e) => error!("Can not remove {:#?}: {:#?}", backup, e),
+        }
+    }
+    if !dry_run {
+        println!("Removed {} backup(s), freeing {}", removed, HumanBytes(freed));
+    }
+}
+
+// Revert a single entry and restore the file it overwrote
+fn revert(entry: &Entry, cfg: &Config, progress: &Progress) -> Result<(), Box<dyn Error>> {
+    if entry.mode == Mode::Symlink || entry.mode == Mode::RelativeSymlink {
+        // The target may be gone already, the link still has to go
+        if !fs::symlink_metadata(&entry.to)?.file_type().is_symlink() {
+            return Err("file is not a symlink anymore".into());
+        }
+    } else {
+        let size = fs::metadata(&entry.to)?.len();
+        if size != entry.size {
+            return Err(format!("file has changed since the run ({} bytes instead of {})", size, entry.size).into());
+        }
+    }
+
+    match entry.mode {
+        Mode::Move => {
+            if entry.from.exists() {
+                return Err(format!("{:#?} exists again", entry.from).into());
+            }
+            if let Some(parent) = entry.from.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            transfer::move_file_or_copy(&entry.to, &entry.from, cfg.verify, progress)?;
+        },
+        Mode::Copy | Mode::Hardlink | Mode::Reflink | Mode::Symlink | Mode::RelativeSymlink => fs::remove_file(&entry.to)?,
+    }
+
+    if let Some(backup) = &entry.backup {
+        if fs::symlink_metadata(backup).is_ok() {
+            fs::rename(backup, &entry.to)?;
+        } else {
+            warn!("Backup {:#?} has been pruned, {:#?} can not be restored", backup, entry.to);
+        }
+    }
+    Ok(())
+}
+
+// Remove the directories a file was in as long as they are empty, keeping the library root and its top level folders (e.g. "Movies")
+pub fn remove_empty_parents(path: &Path, root: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if !d.starts_with(root) || d.parent() == Some(root) || d == root || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
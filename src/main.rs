@@ -3,21 +3,41 @@ mod directory;
 mod movie;
 mod show;
 mod media;
+mod review;
+mod transfer;
+mod journal;
+mod conflict;
+mod progress;
+mod scheduler;
+mod preflight;
+mod permissions;
+mod naming;
+mod tmdb;
+mod reorganize;
+mod nfo;
+mod artwork;
+mod cache;
+mod decisions;
+mod fixtures;
+mod release;
 
 use log::*;
-use clap::Parser;
-use std::{path::PathBuf, env, fs};
+use clap::{Parser, Subcommand};
+use std::{path::PathBuf, env, io::{self, IsTerminal}};
 use inline_colorization::*;
 
+use transfer::Mode;
+use fixtures::Fixtures;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Quiet mode
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     quiet: bool,
 
     /// Verbosity
-    #[arg(short, long, action = clap::ArgAction::Count)]
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
     verbose: u8,
 
     /// First run mode
@@ -28,20 +48,106 @@ struct Args {
     #[arg(short, long="move")]
     moov: bool,
 
+    /// Hardlink files rather than copying them (source and library on the same file system)
+    #[arg(long, conflicts_with_all = ["moov", "reflink"])]
+    link: bool,
+
+    /// Clone files copy-on-write rather than copying them (e.g. on Btrfs or XFS)
+    #[arg(long, conflicts_with_all = ["moov", "link"])]
+    reflink: bool,
+
     /// Output moves/copies instead of actually doing them
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     dry_run: bool,
 
+    /// Never prompt, pick matches automatically and put uncertain files on the needs review list
+    #[arg(short, long)]
+    non_interactive: bool,
+
+    /// What to do with files that already exist in the library, overrides the config
+    #[arg(long, value_enum, value_name = "POLICY")]
+    on_conflict: Option<conflict::Policy>,
+
+    /// Symlink files into the library rather than copying them, pointing back at the originals
+    #[arg(long, conflicts_with_all = ["moov", "link", "reflink"])]
+    symlink: bool,
+
+    /// Use relative instead of absolute symlink targets
+    #[arg(long, requires = "symlink")]
+    relative: bool,
+
+    /// Verify copies with checksums before deleting anything, enabled permanently by `verify` in the config
+    #[arg(long)]
+    verify: bool,
+
+    /// Ask TMDB again instead of using cached responses, and do not cache new ones
+    #[arg(long, global = true)]
+    no_cache: bool,
+
+    /// Record TMDB responses to fixture files in DIR
+    #[arg(long, value_name = "DIR", global = true, conflicts_with = "tmdb_replay")]
+    tmdb_record: Option<PathBuf>,
+
+    /// Serve TMDB responses from fixture files in DIR instead of asking TMDB
+    #[arg(long, value_name = "DIR", global = true)]
+    tmdb_replay: Option<PathBuf>,
+
+    /// Ask again instead of replaying answers given for the same files on earlier runs, and do not record new ones
+    #[arg(long)]
+    ignore_decisions: bool,
+
+    /// Forget answers given for files in PATH on earlier runs, then ask again
+    #[arg(long, conflicts_with = "ignore_decisions")]
+    forget_decisions: bool,
+
     /// Look for shows instead of movies
     #[arg(short, long)]
     shows: bool,
 
     /// Custom config file
-    #[arg(short, long, value_name = "FILE")]
+    #[arg(short, long, value_name = "FILE", global = true)]
     config: Option<PathBuf>,
 
     /// Path to look for media in
     path: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Revert the last run (or the given one) by moving files back and removing directories left empty
+    Undo {
+        /// Run to revert instead of the last one
+        #[arg(short, long)]
+        run: Option<u64>,
+
+        /// List all recorded runs instead of reverting one
+        #[arg(short, long)]
+        list: bool,
+
+        /// Remove the files kept for overwritten destinations instead of reverting a run
+        #[arg(short, long, conflicts_with_all = ["run", "list"])]
+        prune: bool,
+
+        /// Only prune backups of runs older than this, e.g. "30days"
+        #[arg(long, value_name = "DURATION", requires = "prune")]
+        older_than: Option<humantime::Duration>,
+    },
+    /// Move files already in the library to where the current naming templates put them
+    Reorganize,
+    /// Manage the cache of TMDB responses
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Remove all cached TMDB responses
+    Clear,
 }
 
 fn main() {
@@ -56,64 +162,123 @@ fn main() {
         .unwrap();
 
     // Set config path config to home folder, or if provided to specified file
-    let config_path = if args.config.is_none() {
-        PathBuf::from(home::home_dir().unwrap_or_default()).join(".plex-media-ingest").join("config.json")
-    } else {
-        args.config.unwrap()
+    let config_path = match args.config {
+        Some(config) => config,
+        None => home::home_dir().unwrap_or_default().join(".plex-media-ingest").join("config.json")
     };
 
     info!("Loading config from \"{}\"", config_path.to_str().unwrap());
 
+    // The first run wizard needs a user, so there is nothing sensible to do without a config in non-interactive mode
+    if args.non_interactive && (args.first_run || !config_path.exists()) {
+        error!("No config found at \"{}\", run once interactively to create it", config_path.display());
+        std::process::exit(1);
+    }
+
     // Read config, or run first run wizard and write config, if none can be found
-    let cfg = config::load(&config_path, args.first_run).unwrap();
+    let mut cfg = match config::load(&config_path, args.first_run) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    cfg.non_interactive = args.non_interactive;
+    cfg.verify |= args.verify;
+    cfg.progress = !args.quiet && io::stderr().is_terminal();
+    cfg.no_cache = args.no_cache;
+    cfg.ignore_decisions = args.ignore_decisions;
+    // Fixtures should hold every response of the run, not just the ones missing from the cache
+    cfg.fixtures = match (args.tmdb_record, args.tmdb_replay) {
+        (Some(dir), _) => Fixtures::Record(dir),
+        (_, Some(dir)) => Fixtures::Replay(dir),
+        _ => Fixtures::Off,
+    };
+    cfg.no_cache |= !matches!(cfg.fixtures, Fixtures::Off);
 
     info!("Found config: {:#?}", cfg);
 
-    // Use either provided or current path as search path for movies/shows
-    let search_path = if args.path.is_none() {
-        env::current_dir().unwrap()
-    } else {
-        args.path.unwrap()
-    };
-
-    // Search path and put everything in vector to hold all the file moves (or copies)
-    let moves = directory::search_path(search_path, cfg, args.shows).unwrap();
-
-    for move_file in moves {
-        if args.moov {
-            // Move files instead of copying
-            println!("Moving {style_bold}{color_red}{}{color_reset}{style_reset} -> {style_bold}{color_green}{}{color_reset}{style_reset}", move_file.from.display(), move_file.to.display());
-            if args.dry_run {
-                continue;
+    match args.command {
+        Some(Command::Undo { run, list, prune, older_than }) => {
+            if list {
+                journal::list(&cfg);
+            } else if prune {
+                journal::prune(&cfg, older_than.map(Into::into), args.dry_run);
+            } else if let Err(e) = journal::undo(&cfg, run, args.dry_run) {
+                error!("Undo failed: {}", e);
             }
-            fs::create_dir_all(move_file.to.parent().unwrap()).unwrap();
-            match fs::rename(&move_file.from, &move_file.to) {
-                Ok(_) => continue,
-                Err(e) => {
-                    warn!("Can not rename, error {:#?}, copying and deleting instead", e);
-                    match fs::copy(&move_file.from, &move_file.to) {
-                        Ok(_) => _ = fs::remove_file(&move_file.from),
-                        Err(e) => {
-                            error!("Copy also failed with error {:#?}", e);
-                            continue;
-                        }
-                    }
-                }
+            return;
+        },
+        Some(Command::Reorganize) => {
+            let moves = reorganize::plan(&cfg);
+            let moves = conflict::resolve(moves, args.on_conflict.unwrap_or(cfg.conflict_policy));
+            if !args.dry_run {
+                preflight::check_or_exit(&moves, Mode::Move, &cfg);
             }
-        } else {
-            // Copy files
-            println!("Copying {style_bold}{color_red}{}{color_reset}{style_reset} -> {style_bold}{color_green}{}{color_reset}{style_reset}", move_file.from.display(), move_file.to.display());
-            if args.dry_run {
-                continue;
+            transfer::execute(moves.clone(), Mode::Move, args.dry_run, &cfg);
+            if !args.dry_run {
+                reorganize::clean_up(&moves, &cfg);
             }
-            fs::create_dir_all(move_file.to.parent().unwrap()).unwrap();
-            match fs::copy(&move_file.from, &move_file.to) {
-                Ok(_) => _ = (),
-                Err(e) => {
-                    error!("Copy failed with error {:#?}", e);
-                    continue;
-                }
+            return;
+        },
+        Some(Command::Cache { command: CacheCommand::Clear }) => {
+            match cache::clear(&cfg) {
+                Ok(removed) => println!("Removed {} cached TMDB response(s) from \"{}\"", removed, cache::dir(&cfg).display()),
+                Err(e) => error!("Can not clear cache: {}", e),
             }
+            return;
+        },
+        None => (),
+    }
+
+    // Use either provided or current path as search path for movies/shows
+    let search_path = match args.path {
+        Some(path) => path,
+        None => env::current_dir().unwrap()
+    };
+
+    if args.forget_decisions {
+        let forgotten = decisions::forget(&cfg, &search_path);
+        info!("Forgot {} decision(s) for files in {:#?}", forgotten, search_path);
+    }
+
+    // Symlinked libraries break when downloads get (re)moved, report what is broken already
+    if args.symlink {
+        for link in transfer::dangling_links(&cfg.plex_library) {
+            warn!("Dangling link in library: {:#?}", link);
+        }
+    }
+
+    // Search path and put everything in vector to hold all the file moves (or copies)
+    let run_start = media::timestamp();
+    let moves = directory::search_path(search_path, cfg.clone(), args.shows).unwrap();
+
+    let moves = conflict::resolve(moves, args.on_conflict.unwrap_or(cfg.conflict_policy));
+
+    let mode = if args.moov {
+        Mode::Move
+    } else if args.link {
+        Mode::Hardlink
+    } else if args.reflink {
+        Mode::Reflink
+    } else if args.symlink && args.relative {
+        Mode::RelativeSymlink
+    } else if args.symlink {
+        Mode::Symlink
+    } else {
+        Mode::Copy
+    };
+    if !args.dry_run {
+        preflight::check_or_exit(&moves, mode, &cfg);
+    }
+    transfer::execute(moves, mode, args.dry_run, &cfg);
+
+    // List everything that could not be matched automatically
+    let reviews = review::since(&cfg, run_start);
+    if !reviews.is_empty() {
+        println!("{style_bold}{color_yellow}{} file(s) need review{color_reset}{style_reset} (full list in \"{}\"):", reviews.len(), review::list_path(&cfg).display());
+        for r in reviews {
+            println!("  {}: {}", r.path.display(), r.reason);
         }
     }
 }
\ No newline at end of file
@@ -1,12 +1,36 @@
-use std::{path::PathBuf, error::Error, fs::File, cmp, io::Read};
+use std::{path::{Path, PathBuf}, error::Error, fs::File, cmp, io::Read, fmt, time::{SystemTime, UNIX_EPOCH}};
 
-use log::trace;
+use inquire::{Text, Confirm, CustomType, Select};
+use inline_colorization::*;
+use log::{info, warn, error};
+use serde::de::DeserializeOwned;
+
+use crate::{config::Config, decisions, release, review, tmdb::{self, Found, Kind, Search}};
 
 // Struct holding two paths for the move/copy command
 #[derive(Debug, Clone)]
 pub struct Move {
     pub from: PathBuf,
-    pub to: PathBuf
+    pub to: PathBuf,
+    // Files written next to the destination once the file has been transferred
+    pub sidecars: Vec<Sidecar>,
+}
+
+// File created along with a transferred one, e.g. an NFO file or artwork
+#[derive(Debug, Clone)]
+pub struct Sidecar {
+    pub path: PathBuf,
+    pub contents: Contents,
+    // Belongs to the movie, show or season rather than the file (e.g. "tvshow.nfo"), any of its files being transferred writes it
+    pub item: bool,
+}
+
+// What goes into a sidecar file
+#[derive(Debug, Clone)]
+pub enum Contents {
+    Text(String),
+    // Downloaded from the URL
+    Download(String),
 }
 
 // Extract the header/magic bytes from a file
@@ -22,54 +46,298 @@ pub fn get_file_header(path: PathBuf) -> Result<Vec<u8>, Box<dyn Error>> {
     Ok(bytes)
 }
 
-// Check validity of a file-/foldername token (strip common torrent parts)
-fn token_valid(t: &&str) -> bool {
-    if
-        t.eq_ignore_ascii_case("dvd") ||
-        t.eq_ignore_ascii_case("bluray") ||
-        t.eq_ignore_ascii_case("webrip") ||
-        t.eq_ignore_ascii_case("youtube") ||
-        t.eq_ignore_ascii_case("download") ||
-        t.eq_ignore_ascii_case("web") ||
-        t.eq_ignore_ascii_case("uhd") ||
-        t.eq_ignore_ascii_case("hd") ||
-        t.eq_ignore_ascii_case("tv") ||
-        t.eq_ignore_ascii_case("tvrip") ||
-        t.eq_ignore_ascii_case("1080p") ||
-        t.eq_ignore_ascii_case("1080i") ||
-        t.eq_ignore_ascii_case("2160p") ||
-        t.eq_ignore_ascii_case("x264") ||
-        t.eq_ignore_ascii_case("x265") ||
-        t.eq_ignore_ascii_case("h265") ||
-        t.eq_ignore_ascii_case("dts") ||
-        t.eq_ignore_ascii_case("hevc") ||
-        t.eq_ignore_ascii_case("10bit") ||
-        t.eq_ignore_ascii_case("12bit") ||
-        t.eq_ignore_ascii_case("hdr") ||
-        t.eq_ignore_ascii_case("xvid") ||
-        t.eq_ignore_ascii_case("AAC5") ||
-        t.eq_ignore_ascii_case("AAC") ||
-        t.eq_ignore_ascii_case("AC3") ||
-        t.eq_ignore_ascii_case("remux") ||
-        t.eq_ignore_ascii_case("atmos") ||
-        t.eq_ignore_ascii_case("pdtv") ||
-        t.eq_ignore_ascii_case("td") ||
-        t.eq_ignore_ascii_case("internal") ||
-        t.eq_ignore_ascii_case("ma") ||
-        t.eq_ignore_ascii_case("sample") ||             // This just removes the word sample, maybe we want to ban files with the word sample all together
-        (t.starts_with('[') || t.ends_with(']')) ||
-        (t.starts_with('(') || t.ends_with(')')) ||
-        (t.starts_with('{') || t.ends_with('}')) ||
-        (t.starts_with(['s','S']) && t.len() == 3 && t.chars().next().map(char::is_numeric).unwrap_or(false))      // Season specifier
-    {
-        return false;
+// Seconds since the unix epoch, used to timestamp everything written to disk
+pub fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// Check if a token is a plausible release year
+fn parse_year_token(t: &str) -> Option<i32> {
+    if t.len() != 4 || !t.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let year: i32 = t.parse().ok()?;
+    if (1900..=2099).contains(&year) {
+        Some(year)
+    } else {
+        None
+    }
+}
+
+// Year of a TMDB release or first air date (e.g. "2019-05-31"), for naming files
+pub fn release_year(date: Option<&str>) -> Option<String> {
+    date?.split('-').next().filter(|y| !y.is_empty()).map(String::from)
+}
+
+// Lowercase alphanumeric words of a title, for comparing titles from file names with titles from TMDB
+fn normalise_words(s: &str) -> Vec<String> {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+// Score how well a TMDB title and release date match the title tokens and year parsed from the file name, from 0.0 (no match) to 1.0 (exact match)
+pub fn match_score(title_tokens: &[String], year: Option<i32>, title: &str, date: Option<&str>) -> f64 {
+    let wanted = normalise_words(&title_tokens.join(" "));
+    let found = normalise_words(title);
+    if wanted.is_empty() || found.is_empty() {
+        return 0.0;
+    }
+
+    // Dice coefficient over the words of both titles, exact matches (same words in the same order) always score 1.0
+    let similarity = if wanted == found {
+        1.0
+    } else {
+        let mut remaining = found.clone();
+        let mut common = 0;
+        for w in &wanted {
+            if let Some(pos) = remaining.iter().position(|f| f == w) {
+                remaining.remove(pos);
+                common += 1;
+            }
+        }
+        2.0 * common as f64 / (wanted.len() + found.len()) as f64
+    };
+
+    let Some(year) = year else { return similarity; };
+    let found_year = date.and_then(|d| d.split('-').next()).and_then(parse_year_token);
+    let year_score = match found_year {
+        Some(y) if y == year => 1.0,
+        Some(y) if (y - year).abs() == 1 => 0.5,
+        _ => 0.0,
+    };
+    0.75 * similarity + 0.25 * year_score
+}
+
+// Pick the best scored TMDB result in non-interactive mode, or put the file on the needs review list if no result is good enough
+// or the runner-up scored about as well
+pub fn auto_select<T: fmt::Display>(path: &Path, mut scored: Vec<(f64, T)>, cfg: &Config) -> Option<T> {
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let candidates: Vec<String> = scored.iter().take(5).map(|(score, option)| format!("{:.2} {}", score, option)).collect();
+    let runner_up = scored.get(1).map(|(score, _)| *score);
+    match scored.into_iter().next() {
+        Some((score, _)) if score < cfg.match_threshold => {
+            warn!("Best match for {:#?} only scored {:.2}, adding to needs review list", path, score);
+            review::add(cfg, path, "No TMDB result scored above the match threshold", candidates);
+            None
+        },
+        Some((score, option)) => match runner_up {
+            Some(second) if score - second < cfg.match_margin => {
+                warn!("Best match {} for {:#?} scored {:.2}, the next one {:.2}, adding to needs review list", option, path, score, second);
+                review::add(cfg, path, "Several TMDB results scored about the same", candidates);
+                None
+            },
+            _ => {
+                info!("Matched {:#?} to {} with score {:.2}", path, option, score);
+                Some(option)
+            },
+        },
+        None => {
+            review::add(cfg, path, "No TMDB results", candidates);
+            None
+        }
+    }
+}
+
+// Entry of the selection dialog, a TMDB result or another way to find the right one
+enum Choice<T> {
+    Result(T),
+    NextPage(i32, i32),
+    Search,
+    TmdbId,
+    ImdbId,
+    Skip,
+}
+
+impl<T: fmt::Display> fmt::Display for Choice<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Choice::Result(result) => write!(f, "{}", result),
+            Choice::NextPage(page, pages) => write!(f, "Show next page ({} of {})", page, pages),
+            Choice::Search => write!(f, "Search again with custom text"),
+            Choice::TmdbId => write!(f, "Enter TMDB ID"),
+            Choice::ImdbId => write!(f, "Enter IMDb ID"),
+            Choice::Skip => write!(f, "Skip"),
+        }
     }
-    true
 }
 
-// Separate file-/foldernames into a vector of tokens, stripping of whitespace or other separation characters
-pub fn tokenize_media_name(file_name: String) -> Vec<String> {
-    let tokens: Vec<String> = file_name.split(&['-', ' ', ':', '@', '.'][..]).filter(|t| token_valid(t)).map(String::from).collect();
-    trace!("Tokens are: {:#?}", tokens);
-    tokens
-}
\ No newline at end of file
+// Let the user pick a TMDB result, searching again, looking up ids or paging through results until one is picked or skipped
+pub fn select<T: DeserializeOwned + Clone + fmt::Display>(prompt: &str, kind: Kind, found: Option<Found<T>>, cfg: &Config) -> Option<T> {
+    let (mut query, mut year, mut search) = match found {
+        Some(found) => (found.query, found.year, found.search),
+        None => (String::new(), None, Search { results: Vec::new(), total_results: 0, page: 0, total_pages: 0 }),
+    };
+    loop {
+        let mut options: Vec<Choice<T>> = search.results.iter().cloned().map(Choice::Result).collect();
+        if search.page < search.total_pages {
+            options.push(Choice::NextPage(search.page + 1, search.total_pages));
+        }
+        options.extend([Choice::Search, Choice::TmdbId, Choice::ImdbId, Choice::Skip]);
+
+        let choice = match Select::new(prompt, options).with_page_size(12).prompt() {
+            Ok(choice) => choice,
+            Err(e) => {
+                error!("Error while selecting content: {:#?}", e);
+                return None;
+            }
+        };
+        // Cancelled text prompts go back to the selection
+        match choice {
+            Choice::Result(result) => return Some(result),
+            Choice::Skip => return None,
+            Choice::NextPage(page, _) => match tmdb::search(cfg, kind, &query, year, page) {
+                Ok(next) => search = next,
+                Err(e) => error!("Fetching page {} for {:#?} from TMDB failed: {}", page, query, e),
+            },
+            Choice::Search => {
+                let Ok(text) = Text::new("Search TMDB for:").with_initial_value(&query).prompt() else { continue; };
+                let text = text.trim().to_string();
+                if text.is_empty() {
+                    continue;
+                }
+                match tmdb::search(cfg, kind, &text, None, 1) {
+                    Ok(next) if next.total_results > 0 => {
+                        (query, year, search) = (text, None, next);
+                    },
+                    Ok(_) => warn!("Nothing found on TMDB for {:#?}", text),
+                    Err(e) => error!("Searching TMDB for {:#?} failed: {}", text, e),
+                }
+            },
+            Choice::TmdbId => {
+                let Ok(id) = CustomType::<i32>::new("TMDB ID:").with_error_message("Not a number").prompt() else { continue; };
+                if let Some(result) = tmdb::details(cfg, kind, id) {
+                    return Some(result);
+                }
+            },
+            Choice::ImdbId => {
+                let Ok(id) = Text::new("IMDb ID (e.g. tt0133093):").prompt() else { continue; };
+                if let Some(result) = tmdb::find(cfg, kind, id.trim(), "imdb_id") {
+                    return Some(result);
+                }
+            },
+        }
+    }
+}
+
+// Find the vertical resolution in a file or folder name (e.g. "1080p", "2160p", "4K")
+pub fn resolution(name: &str) -> Option<u32> {
+    release::parse(name).resolution
+}
+
+// Check if the extension belongs to a subtitle file
+pub fn is_subtitle(file: &Path) -> bool {
+    match file.extension() {
+        Some(ext) => {
+            ext.eq_ignore_ascii_case("srt") ||
+            ext.eq_ignore_ascii_case("ass") ||
+            ext.eq_ignore_ascii_case("ssa") ||
+            ext.eq_ignore_ascii_case("smi") ||
+            ext.eq_ignore_ascii_case("pgs") ||
+            ext.eq_ignore_ascii_case("vob")
+        },
+        None => false,
+    }
+}
+
+// Read language code and forced flag from the name of a subtitle file (e.g. "Movie.en.forced.srt")
+pub fn subtitle_tags(file: &Path) -> (Option<String>, bool) {
+    let stem = file.file_stem().unwrap_or_default().to_str().unwrap_or_default();
+    let tokens: Vec<&str> = stem.split(['.', '_', ' ', '-']).collect();
+    let forced = tokens.iter().any(|t| t.eq_ignore_ascii_case("forced"));
+    let lang = tokens.iter().skip(1).rev().find(|t| t.len() == 2 && t.chars().all(|c| c.is_ascii_alphabetic()));
+    // Languages can also be spelled out, e.g. "Movie.German.srt"
+    let lang = lang.map(|l| l.to_ascii_lowercase()).or_else(|| release::parse(stem).languages.into_iter().next());
+    (lang, forced)
+}
+
+// Find language code and forced flag of a subtitle file, prompting the user or, in non-interactive mode, reading them from the file name (e.g. "Movie.en.forced.srt")
+pub fn subtitle_language(file: &Path, cfg: &Config) -> Option<(String, bool)> {
+    // Replay what has been answered for this file on an earlier run
+    if let Some(decision) = decisions::get(cfg, file) {
+        if decision.skip {
+            return None;
+        }
+        if let Some(lang) = decision.lang {
+            return Some((lang, decision.forced));
+        }
+    }
+
+    if cfg.non_interactive {
+        let (lang, forced) = subtitle_tags(file);
+        return match lang {
+            Some(lang) => Some((lang, forced)),
+            None => {
+                warn!("Can not find subtitle language in file name of {:#?}, adding to needs review list", file);
+                review::add(cfg, file, "Subtitle language unknown", Vec::new());
+                None
+            }
+        };
+    }
+
+    let lang_code = Text::new(format!("Specify ISO-639-1 (2-letter) language code (e.g. 'en', 'de') or leave empty to discard for {style_bold}{}{style_reset}:", file.display()).as_str()).prompt();
+    match lang_code {
+        Ok(lang_code) => {
+            if lang_code.is_empty() {
+                decisions::record(cfg, file, |d| d.skip = true);
+                return None;
+            }
+            match Confirm::new("Is this a forced sub?").with_default(false).prompt() {
+                Ok(forced) => {
+                    let lang_code = lang_code.to_ascii_lowercase();
+                    decisions::record(cfg, file, |d| {
+                        d.lang = Some(lang_code.clone());
+                        d.forced = forced;
+                    });
+                    Some((lang_code, forced))
+                },
+                Err(e) => {
+                    error!("There was an error: {:#?}", e);
+                    None
+                },
+            }
+        },
+        Err(e) => {
+            error!("There was an error: {:#?}", e);
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(title: &str) -> Vec<String> {
+        title.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn scores() {
+        assert_eq!(match_score(&tokens("The Matrix"), Some(1999), "The Matrix", Some("1999-03-31")), 1.0);
+        assert_eq!(match_score(&tokens("the.matrix"), None, "The Matrix", None), 1.0);
+        assert_eq!(match_score(&tokens("The Matrix"), Some(1999), "The Matrix", Some("2000-01-01")), 0.875);
+        assert_eq!(match_score(&tokens("The Matrix"), Some(1999), "The Matrix", Some("2003-05-15")), 0.75);
+        assert_eq!(match_score(&tokens("The Matrix"), None, "The Matrix Reloaded", None), 0.8);
+        assert_eq!(match_score(&tokens("The Matrix"), None, "Inception", None), 0.0);
+        assert_eq!(match_score(&[], None, "Inception", None), 0.0);
+    }
+
+    #[test]
+    fn auto_selection() {
+        let cfg = crate::config::scratch("auto-select");
+        let path = Path::new("The.Matrix.1999.mkv");
+        assert_eq!(auto_select(path, vec![(1.0, "The Matrix"), (0.6, "The Matrix Reloaded")], &cfg), Some("The Matrix"));
+        assert_eq!(auto_select(path, vec![(0.6, "The Matrix Reloaded"), (1.0, "The Matrix")], &cfg), Some("The Matrix"));
+        assert_eq!(auto_select(path, vec![(1.0, "The Matrix")], &cfg), Some("The Matrix"));
+        assert_eq!(review::since(&cfg, 0).len(), 0);
+
+        // Ties, near-ties, bad matches and no matches go to review
+        assert_eq!(auto_select(path, vec![(1.0, "The Matrix"), (1.0, "The Matrix")], &cfg), None);
+        assert_eq!(auto_select(path, vec![(0.95, "The Matrix"), (0.9, "The Matrix Resurrections")], &cfg), None);
+        assert_eq!(auto_select(path, vec![(0.5, "Inception")], &cfg), None);
+        assert_eq!(auto_select::<&str>(path, Vec::new(), &cfg), None);
+        assert_eq!(review::since(&cfg, 0).len(), 4);
+    }
+}
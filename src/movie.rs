@@ -1,22 +1,12 @@
-use std::{path::PathBuf, fs::DirEntry, fmt, time::Duration};
-use infer;
-use inquire::{Select, Text, Confirm};
+use std::{path::{Path, PathBuf}, fs::DirEntry, fmt};
+use inquire::{Select, Text};
 use log::{info, warn, error, trace, debug};
-use reqwest::{blocking::Client, header::{HeaderMap, HeaderValue}};
+use regex::Regex;
 use serde::Deserialize;
-use urlencoding::encode;
 use inline_colorization::*;
-use sanitise_file_name::sanitise;
 use walkdir::WalkDir;
 
-use crate::{config::Config, directory::search_path, media::{self, Move, get_file_header}};
-
-// Struct to hold the TMDB API response
-#[derive(Deserialize, Debug)]
-struct TMDBResponse {
-    results: Vec<TMDBEntry>,
-    total_results: i32
-}
+use crate::{config::Config, directory::search_path, media::{self, Move, get_file_header}, naming::{self, Fields}, nfo, artwork, review, decisions, release::{self, Release}, tmdb::{self, Kind}};
 
 // Struct to hold a movie from the TMDB API response
 #[derive(Deserialize, Debug, Clone)]
@@ -25,8 +15,15 @@ struct TMDBEntry {
     title: String,
     original_language: Option<String>,
     release_date: Option<String>,
+    overview: Option<String>,
+    // Only looked up if a naming template needs it
+    #[serde(default)]
+    imdb_id: Option<String>,
 }
 
+// Folders Plex looks for extras in
+pub const EXTRA_TYPES: [&str; 8] = ["Behind The Scenes", "Deleted Scenes", "Featurettes", "Interviews", "Scenes", "Shorts", "Trailers", "Other"];
+
 // Display implementation for the inquire selection dialog
 impl fmt::Display for TMDBEntry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -35,73 +32,61 @@ impl fmt::Display for TMDBEntry {
 }
 
 // Look up movie on the TMDB API
-fn lookup_movie(file_name: PathBuf, mut name_tokens: Vec<String>, cfg: Config) -> Option<TMDBEntry> {
-    let mut h = HeaderMap::new();
-    h.insert("Accept", HeaderValue::from_static("application/json"));
-    h.insert("Authorization", HeaderValue::from_str(format!("Bearer {}", cfg.tmdb_key).as_str()).unwrap());
-    
-    let client = Client::builder()
-        .default_headers(h)
-        .build().unwrap();
-
-    let mut response: TMDBResponse;
-    loop {
-        if name_tokens.len() == 0 {
-            error!("Could not find title on TMDB!");
-            return None;
-        }
-
-        let name = name_tokens.join(" ");
-        trace!("Searching on TMDB for {:#?}", name);
-
-        let http_response = client
-            .get(format!("https://api.themoviedb.org/3/search/movie?query={}&include_adult=false&language=en-US&page=1", encode(name.as_str()).into_owned()))
-            .timeout(Duration::from_secs(120))
-            .send();
-
-        if http_response.is_err() {
-            warn!("Request error: {:#?}", http_response.unwrap_err());
+fn lookup_movie(file_name: PathBuf, release: Release, cfg: Config) -> Option<TMDBEntry> {
+    let found = match tmdb::search_release::<TMDBEntry>(&cfg, Kind::Movie, &release) {
+        Ok(found) => found,
+        Err(e) => {
+            error!("Searching TMDB for {:#?} failed: {}", release.title, e);
+            if cfg.non_interactive {
+                review::add(&cfg, &file_name, &format!("Searching TMDB failed: {}", e), Vec::new());
+            }
             return None;
         }
-
-        response = http_response.unwrap().json::<TMDBResponse>().unwrap();
-        trace!("TMDB Reponse: {:#?}", response);
-
-        if response.total_results == 0 {
-            name_tokens.pop();
-        } else {
-            break;
-        }
+    };
+    if found.is_none() {
+        error!("Could not find title on TMDB!");
     }
 
-    let options = response.results;
-
-    let ans = Select::new(format!("Select movie that matches the file {style_bold}{}{style_reset}:", file_name.display()).as_str(), options).prompt();
-    match ans {
-        Ok(choice) => {
-            debug!("Selected: {:#?}", choice);
-            return Some(choice);
-        },
-        Err(e) => {
-            error!("Error while selecting content: {:#?}", e);
-            return None;
-        },
+    if cfg.non_interactive {
+        let options = found.map(|f| f.search.results).unwrap_or_default();
+        let title_tokens: Vec<String> = release.title.split_whitespace().map(String::from).collect();
+        let scored = options.into_iter().map(|o| (media::match_score(&title_tokens, release.year, &o.title, o.release_date.as_deref()), o)).collect();
+        return media::auto_select(&file_name, scored, &cfg);
     }
+
+    let choice = media::select(format!("Select movie that matches the file {style_bold}{}{style_reset}:", file_name.display()).as_str(), Kind::Movie, found, &cfg)?;
+    debug!("Selected: {:#?}", choice);
+    decisions::record(&cfg, &file_name, |d| d.tmdb_id = Some(choice.id));
+    Some(choice)
 }
 
 // Handle single video file
 fn movie_video_file_handler(entry: PathBuf, cfg: Config) -> Option<TMDBEntry> {
     info!("Found movie video file: {:#?}", entry);
 
+    // Use the movie picked for this file on an earlier run
+    if let Some(id) = decisions::get(&cfg, &entry).and_then(|d| d.tmdb_id) {
+        if let Some(meta) = tmdb::details::<TMDBEntry>(&cfg, Kind::Movie, id) {
+            info!("Using {} picked for {:#?} before", meta, entry);
+            return Some(meta);
+        }
+    }
+
     let file_name = entry.file_name().unwrap_or_default();
     trace!("File name is: {:#?}", file_name);
 
-    let mut name_tokens = media::tokenize_media_name(file_name.to_str().unwrap_or_default().to_string());
-    
-    // Remove last token (file ext)
-    _ = name_tokens.pop();
-    
-    lookup_movie(entry, name_tokens, cfg)
+    let release = release::parse(file_name.to_str().unwrap_or_default());
+
+    // Files named for Plex, Jellyfin or Emby already carry the id, in their name or the name of their folder
+    let folder = release::parse(entry.parent().and_then(|p| p.file_name()).unwrap_or_default().to_str().unwrap_or_default());
+    for tagged in [&release, &folder] {
+        if let Some(meta) = tmdb::tagged::<TMDBEntry>(&cfg, Kind::Movie, tagged) {
+            info!("Using {} tagged in {:#?}", meta, entry);
+            return Some(meta);
+        }
+    }
+
+    lookup_movie(entry, release, cfg)
 }
 
 // Handler for the sorted vectors of files and folders, gets called recursively for subfolders, if no primary media can be found
@@ -146,6 +131,38 @@ pub fn handle_movie_files_and_folders(files: Vec<DirEntry>, folders: Vec<DirEntr
     moves
 }
 
+// Guess the extra type from the names of the folders a file is in (e.g. "Featurettes/Making Of.mkv"), used in non-interactive mode
+pub fn guess_extra_type(file: &Path) -> Option<&'static str> {
+    let parents = file.parent()?;
+    for folder in parents.iter().rev() {
+        let folder = folder.to_str().unwrap_or_default().to_ascii_lowercase();
+        let folder = folder.trim_end_matches('s');
+        // Emby puts other extras in "Extras", Kodi all of them, so only the file name can tell (e.g. "Extras/Trailer 2.mkv")
+        if folder == "extra" {
+            let stem = file.file_stem()?.to_str().unwrap_or_default();
+            let named = |t: &str| Regex::new(&format!(r"(?i)\b{}s?\b", t.trim_end_matches('s').replace(' ', "[ ._-]"))).unwrap().is_match(stem);
+            return EXTRA_TYPES.into_iter().find(|t| named(t)).or(Some("Other"));
+        }
+        if let Some(extra_type) = EXTRA_TYPES.iter().find(|t| t.to_ascii_lowercase().trim_end_matches('s') == folder) {
+            return Some(extra_type);
+        }
+    }
+    None
+}
+
+// Fields for naming a file belonging to a movie
+fn fields(meta: &TMDBEntry, file: &Path) -> Fields {
+    Fields {
+        title: Some(meta.title.clone()),
+        year: media::release_year(meta.release_date.as_deref()),
+        tmdb_id: Some(meta.id),
+        imdb_id: meta.imdb_id.clone(),
+        resolution: media::resolution(file.file_name().unwrap_or_default().to_str().unwrap_or_default()),
+        ext: file.extension().map(|e| e.to_str().unwrap_or_default().to_string()),
+        ..Fields::default()
+    }
+}
+
 // Check files for movie, or if primary media has been marked as found for extras, show required inquire dialoges
 fn check_movie_file(file: PathBuf, primary_media: &mut Option<TMDBEntry>, cfg: &Config, moves: &mut Vec<Move>) {
     trace!("Checking {:#?}", file);
@@ -157,33 +174,72 @@ fn check_movie_file(file: PathBuf, primary_media: &mut Option<TMDBEntry>, cfg: &
                     None => {
                         // No primary media found yet, look up media on TMDB
                         match movie_video_file_handler(file.clone(), cfg.clone()) {
-                            Some(meta) => {
+                            Some(mut meta) => {
+                                let templates = &cfg.templates;
+                                if cfg.nfo || [&templates.movie, &templates.movie_extra, &templates.movie_subtitle].iter().any(|t| t.uses("imdb_id")) {
+                                    meta.imdb_id = tmdb::imdb_id(cfg, Kind::Movie, meta.id);
+                                }
                                 *primary_media = Some(meta.clone());
-                                let original_path = file;
-                                let ext = original_path.extension().unwrap_or_default();
-                                let year: String;
-                                match meta.release_date.unwrap_or_default().split('-').nth(0) {
-                                    Some(y) => year = format!("({}) ", y),
-                                    None => year = "".to_string()
+                                // Editions in the release name (e.g. "Movie.2010.Extended.1080p") carry over to the library
+                                let edition = release::parse(file.file_name().unwrap_or_default().to_str().unwrap_or_default()).edition;
+                                let fields = Fields { edition, ..fields(&meta, &file) };
+                                if let Some(m) = naming::push(moves, file.clone(), &templates.movie, &fields, cfg) {
+                                    if cfg.nfo {
+                                        m.sidecars.push(nfo::movie(&m.to, &fields, meta.overview.as_deref(), cfg));
+                                    }
+                                    m.sidecars.append(&mut artwork::item(Kind::Movie, meta.id, &naming::item_dir(&m.to, &fields, cfg), cfg));
                                 }
-                                let new_path = cfg.plex_library.join(format!("Movies/{0} {3}{{tmdb-{1}}}/{0} {3}{{tmdb-{1}}}.{2}", sanitise(meta.title.as_str()), meta.id, ext.to_str().unwrap_or_default(), year));
-                                moves.push(Move { from: original_path, to: new_path });
                             },
                             None => {
                                 warn!("Could not find a TMDB entry for {:#?}", file);
-                                return;
                             },
                         }
                     },
                     Some(primary_media) => {
                         // No additional TMDB lookup needed, treat media as extras
-                        let extra_types: Vec<&str> = vec!["Ignore", "Edition", "Behind The Scenes", "Deleted Scenes", "Featurettes", "Interviews", "Scenes", "Shorts", "Trailers", "Other"];
+                        let extra_types: Vec<&str> = ["Ignore", "Edition"].into_iter().chain(EXTRA_TYPES).collect();
+                        let initial_value = file.file_stem().unwrap_or_default().to_str().unwrap_or_default();
+
+                        // Replay what has been answered for this file on an earlier run
+                        if let Some(decision) = decisions::get(cfg, &file) {
+                            if decision.skip {
+                                return;
+                            }
+                            if let Some(edition) = decision.edition {
+                                let fields = Fields { edition: Some(edition), ..fields(primary_media, &file) };
+                                naming::push(moves, file.clone(), &cfg.templates.movie, &fields, cfg);
+                                return;
+                            }
+                            if let Some(extra_type) = decision.extra_type {
+                                let extra_name = decision.extra_name.unwrap_or(initial_value.to_string());
+                                let fields = Fields { extra_type: Some(cfg.profile.extra_folder(&extra_type)), extra_name: Some(extra_name), ..fields(primary_media, &file) };
+                                naming::push(moves, file.clone(), &cfg.templates.movie_extra, &fields, cfg);
+                                return;
+                            }
+                        }
+
+                        if cfg.non_interactive {
+                            // Only folder names can tell what kind of extra this is, everything else needs a human
+                            match guess_extra_type(&file) {
+                                Some(choice) => {
+                                    let fields = Fields { extra_type: Some(cfg.profile.extra_folder(choice)), extra_name: Some(initial_value.to_string()), ..fields(primary_media, &file) };
+                                    naming::push(moves, file.clone(), &cfg.templates.movie_extra, &fields, cfg);
+                                },
+                                None => {
+                                    warn!("Can not categorize extra {:#?}, adding to needs review list", file);
+                                    review::add(cfg, &file, &format!("Extra or edition of {}", primary_media), Vec::new());
+                                }
+                            }
+                            return;
+                        }
+
                         let ans = Select::new(format!("Select extra type {style_bold}{}{style_reset} (Ignore to ignore the file, Edition to treat it as alternate edition of the main movie):", file.display()).as_str(), extra_types).prompt();
 
                         match ans {
                             Ok(choice) => {
                                 if choice == "Ignore" {
                                     // Ignoring the given file
+                                    decisions::record(cfg, &file, |d| d.skip = true);
                                     return;
                                 }
                                 if choice == "Edition" {
@@ -191,123 +247,53 @@ fn check_movie_file(file: PathBuf, primary_media: &mut Option<TMDBEntry>, cfg: &
                                     let edition_name = Text::new("Specify the edition's name (e.g. Director's Cut, Theatrical Version):").prompt();
                                     match edition_name {
                                         Ok(edition_name) => {
-                                            let original_path = file;
-                                            let ext = original_path.extension().unwrap_or_default();
-                                            let year: String;
-                                            match primary_media.clone().release_date.unwrap_or_default().split('-').nth(0) {
-                                                Some(y) => year = format!("({}) ", y),
-                                                None => year = "".to_string()
-                                            }
-                                            let new_path = cfg.plex_library.join(format!("Movies/{0} {4}{{tmdb-{1}}}/{0} {4}{{tmdb-{1}}} {{edition-{3}}}.{2}", sanitise(primary_media.title.as_str()), primary_media.id, ext.to_str().unwrap_or_default(), edition_name, year));
-                                            moves.push(Move { from: original_path, to: new_path });
-                                            return;
+                                            decisions::record(cfg, &file, |d| d.edition = Some(edition_name.clone()));
+                                            let fields = Fields { edition: Some(edition_name), ..fields(primary_media, &file) };
+                                            naming::push(moves, file.clone(), &cfg.templates.movie, &fields, cfg);
                                         },
                                         Err(e) => {
                                             error!("There was an error: {:#?}", e);
-                                            return;
                                         },
                                     }
+                                    return;
                                 }
-                                let initial_value = file.file_stem().unwrap_or_default().to_str().unwrap_or_default();
                                 let description = Text::new(format!("Give this {} a descriptive name:", choice).as_str()).with_initial_value(initial_value).prompt();
                                 match description {
                                     Ok(description) => {
-                                        let original_path = file;
-                                        let ext = original_path.extension().unwrap_or_default();
-                                        let year: String;
-                                        match primary_media.clone().release_date.unwrap_or_default().split('-').nth(0) {
-                                            Some(y) => year = format!("({}) ", y),
-                                            None => year = "".to_string()
-                                        }
-                                        let new_path = cfg.plex_library.join(format!("Movies/{0} {5}{{tmdb-{1}}}/{3}/{4}.{2}", sanitise(primary_media.title.as_str()), primary_media.id, ext.to_str().unwrap_or_default(), choice, description, year));
-                                        moves.push(Move { from: original_path, to: new_path });
-                                        return;
+                                        decisions::record(cfg, &file, |d| {
+                                            d.extra_type = Some(choice.to_string());
+                                            d.extra_name = Some(description.clone());
+                                        });
+                                        let fields = Fields { extra_type: Some(cfg.profile.extra_folder(choice)), extra_name: Some(description), ..fields(primary_media, &file) };
+                                        naming::push(moves, file.clone(), &cfg.templates.movie_extra, &fields, cfg);
                                     },
                                     Err(e) => {
                                         error!("There was an error: {:#?}", e);
-                                        return;
                                     },
                                 }
                             },
                             Err(e) => {
                                 error!("There was an error: {:#?}", e);
-                                return;
                             },
                         }
                     }
                 }
-            } else {
-                match file.extension() {
-                    Some(ext) => {
-                        if ext.eq_ignore_ascii_case("srt") ||
-                            ext.eq_ignore_ascii_case("ass") ||
-                            ext.eq_ignore_ascii_case("ssa") ||
-                            ext.eq_ignore_ascii_case("smi") ||
-                            ext.eq_ignore_ascii_case("pgs") ||
-                            ext.eq_ignore_ascii_case("vob") {
-                            // Subtitle file
-                            if primary_media.is_none() {
-                                warn!("Can not categorize subtitle file without primary media, skipping.");
-                                return;
-                            }
+            } else if media::is_subtitle(&file) {
+                // Subtitle file
+                let Some(primary_media) = primary_media.as_ref() else {
+                    warn!("Can not categorize subtitle file without primary media, skipping.");
+                    return;
+                };
 
-                            let lang_code = Text::new(format!("Specify ISO-639-1 (2-letter) language code (e.g. 'en', 'de') or leave empty to discard for {style_bold}{}{style_reset}:", file.display()).as_str()).prompt();
-                            match lang_code {
-                                Ok(lang_code) => {
-                                    if lang_code == "" {
-                                        return;
-                                    }
-                                    let forced = Confirm::new("Is this a forced sub?").with_default(false).prompt();
-                                    match forced {
-                                        Ok(true) => {
-                                            // Forced
-                                            let original_path = file;
-                                            let ext = original_path.extension().unwrap_or_default();
-                                            let year: String;
-                                            match primary_media.clone().unwrap().release_date.unwrap_or_default().split('-').nth(0) {
-                                                Some(y) => year = format!("({}) ", y),
-                                                None => year = "".to_string()
-                                            }
-                                            let new_path = cfg.plex_library.join(format!("Movies/{0} {4}{{tmdb-{1}}}/{0} {{tmdb-{1}}}.{3}.forced.{2}", sanitise(primary_media.as_ref().unwrap().title.as_str()), primary_media.as_ref().unwrap().id, ext.to_str().unwrap_or_default(), lang_code.to_ascii_lowercase(), year));
-                                            moves.push(Move { from: original_path, to: new_path });
-                                            return;
-                                        },
-                                        Ok(false) => {
-                                            // Non-forced
-                                            let original_path = file;
-                                            let ext = original_path.extension().unwrap_or_default();
-                                            let year: String;
-                                            match primary_media.clone().unwrap().release_date.unwrap_or_default().split('-').nth(0) {
-                                                Some(y) => year = format!("({}) ", y),
-                                                None => year = "".to_string()
-                                            }
-                                            let new_path = cfg.plex_library.join(format!("Movies/{0} {4}{{tmdb-{1}}}/{0} {{tmdb-{1}}}.{3}.{2}", sanitise(primary_media.as_ref().unwrap().title.as_str()), primary_media.as_ref().unwrap().id, ext.to_str().unwrap_or_default(), lang_code.to_ascii_lowercase(), year));
-                                            moves.push(Move { from: original_path, to: new_path });
-                                            return;
-                                        },
-                                        Err(e) => {
-                                            error!("There was an error: {:#?}", e);
-                                            return;
-                                        },
-                                    }
-                                },
-                                Err(e) => {
-                                    error!("There was an error: {:#?}", e);
-                                    return;
-                                },
-                            }
-                        } else {
-                            info!("Not a video file nor subtitle, skipping");
-                            return;
-                        }
-                    },
-                    None => {
-                        error!("File {:#?} has no file extension", file);
-                        return;
-                    }
-                }
+                let Some((lang_code, forced)) = media::subtitle_language(&file, cfg) else { return; };
+                let fields = Fields { lang: Some(lang_code), forced, ..fields(primary_media, &file) };
+                naming::push(moves, file.clone(), &cfg.templates.movie_subtitle, &fields, cfg);
+            } else if file.extension().is_none() {
+                error!("File {:#?} has no file extension", file);
+            } else {
+                info!("Not a video file nor subtitle, skipping");
             }
         },
         Err(error) => error!("Can not get file header for {:#?}, Error: {:#?}", file, error),
     }
-}
\ No newline at end of file
+}
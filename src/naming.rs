@@ -0,0 +1,419 @@
+use std::{fmt, iter::Peekable, path::{Component, Path, PathBuf}, str::Chars};
+
+use log::error;
+use sanitise_file_name::sanitise;
+use serde::{Serialize, Deserialize};
+
+use crate::{config::Config, media::Move};
+
+// Placeholders every template can use
+const COMMON: [&str; 6] = ["title", "year", "tmdb_id", "imdb_id", "resolution", "ext"];
+
+// Part of a parsed template
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    // Placeholder with the width it gets zero padded to, e.g. {season:02}
+    Placeholder(String, usize),
+    // Optional group, only rendered if all placeholders in it have a value, e.g. <({year}) >
+    Group(Vec<Part>),
+}
+
+// Path template, e.g. "Movies/{title} <({year}) >{{tmdb-{tmdb_id}}}/{title}.{ext}"
+// {{ and }} are literal braces, everything between < and > is left out if a placeholder in it has no value
+#[derive(Clone, Debug)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, String> {
+        let mut chars = source.chars().peekable();
+        let parts = parse_parts(&mut chars, false)?;
+        Ok(Template { source: source.to_string(), parts })
+    }
+
+    // Check if a placeholder is used anywhere in the template, to only look up what is needed
+    pub fn uses(&self, name: &str) -> bool {
+        uses(&self.parts, name)
+    }
+
+    // Render the template, None if a placeholder outside of an optional group has no value
+    pub fn render(&self, fields: &Fields) -> Option<String> {
+        let mut out = String::new();
+        render_parts(&self.parts, fields, &mut out).then_some(out)
+    }
+
+    // Render the template to a path in the library
+    pub fn path(&self, fields: &Fields, cfg: &Config) -> Option<PathBuf> {
+        self.render(fields).map(|p| cfg.plex_library.join(p))
+    }
+
+    // Check that the template only uses the given placeholders and contains the file extension
+    fn check(&self, name: &str, allowed: &[&str]) -> Result<(), String> {
+        let mut used = Vec::new();
+        placeholders(&self.parts, &mut used);
+        if let Some(p) = used.iter().find(|p| !allowed.contains(&p.as_str())) {
+            return Err(format!("Placeholder {{{}}} can not be used in the {} template", p, name));
+        }
+        if !self.uses("ext") {
+            return Err(format!("The {} template has to contain {{ext}}", name));
+        }
+        // Rendered paths get joined to the library root, which an absolute path would replace and ".." would leave
+        let path = Path::new(&self.source);
+        if path.has_root() || path.components().any(|c| matches!(c, Component::ParentDir | Component::Prefix(_))) {
+            return Err(format!("The {} template has to stay inside the library, it can not start with \"/\" or contain \"..\"", name));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Serialize for Template {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Template, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Template::parse(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+fn parse_parts(chars: &mut Peekable<Chars>, in_group: bool) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            },
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(format!("Unclosed placeholder {{{}", placeholder)),
+                    }
+                }
+                let (name, width) = match placeholder.split_once(':') {
+                    Some((name, width)) => (name, width.parse().map_err(|_| format!("Invalid width in placeholder {{{}}}", placeholder))?),
+                    None => (placeholder.as_str(), 0),
+                };
+                if !Fields::NAMES.contains(&name) {
+                    return Err(format!("Unknown placeholder {{{}}}", name));
+                }
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(Part::Placeholder(name.to_string(), width));
+            },
+            '}' => return Err("Unmatched } (use }} for a literal brace)".to_string()),
+            '<' => {
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(Part::Group(parse_parts(chars, true)?));
+            },
+            '>' if in_group => {
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(literal));
+                }
+                return Ok(parts);
+            },
+            '>' => return Err("Unmatched >".to_string()),
+            c => literal.push(c),
+        }
+    }
+
+    if in_group {
+        return Err("Unclosed optional group <".to_string());
+    }
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    Ok(parts)
+}
+
+fn uses(parts: &[Part], name: &str) -> bool {
+    parts.iter().any(|p| match p {
+        Part::Placeholder(n, _) => n == name,
+        Part::Group(inner) => uses(inner, name),
+        Part::Literal(_) => false,
+    })
+}
+
+fn placeholders(parts: &[Part], used: &mut Vec<String>) {
+    for part in parts {
+        match part {
+            Part::Placeholder(n, _) => used.push(n.clone()),
+            Part::Group(inner) => placeholders(inner, used),
+            Part::Literal(_) => (),
+        }
+    }
+}
+
+fn render_parts(parts: &[Part], fields: &Fields, out: &mut String) -> bool {
+    for part in parts {
+        match part {
+            Part::Literal(s) => out.push_str(s),
+            Part::Placeholder(name, width) => match fields.value(name) {
+                Some(v) => out.push_str(&format!("{:0>width$}", v, width = width)),
+                None => return false,
+            },
+            Part::Group(inner) => {
+                let mut group = String::new();
+                if render_parts(inner, fields, &mut group) {
+                    out.push_str(&group);
+                }
+            },
+        }
+    }
+    true
+}
+
+// Everything known about a file that can end up in its name
+#[derive(Clone, Debug, Default)]
+pub struct Fields {
+    pub title: Option<String>,
+    pub year: Option<String>,
+    pub tmdb_id: Option<i32>,
+    pub imdb_id: Option<String>,
+    pub season: Option<i32>,
+    pub episode: Option<i32>,
+    pub episode_title: Option<String>,
+    pub edition: Option<String>,
+    pub lang: Option<String>,
+    pub forced: bool,
+    pub resolution: Option<u32>,
+    pub ext: Option<String>,
+    pub extra_type: Option<String>,
+    pub extra_name: Option<String>,
+}
+
+impl Fields {
+    const NAMES: [&'static str; 14] = ["title", "year", "tmdb_id", "imdb_id", "season", "episode", "episode_title", "edition", "lang", "forced", "resolution", "ext", "extra_type", "extra_name"];
+
+    // Value of a placeholder, text is sanitised so it can not add path separators or characters file systems do not allow
+    fn value(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "title" => self.title.as_deref().map(sanitise),
+            "year" => self.year.clone(),
+            "tmdb_id" => self.tmdb_id.map(|i| i.to_string()),
+            "imdb_id" => self.imdb_id.clone(),
+            "season" => self.season.map(|s| s.to_string()),
+            "episode" => self.episode.map(|e| e.to_string()),
+            "episode_title" => self.episode_title.as_deref().map(sanitise),
+            "edition" => self.edition.as_deref().map(sanitise),
+            "lang" => self.lang.as_deref().map(sanitise),
+            "forced" => self.forced.then(|| "forced".to_string()),
+            "resolution" => self.resolution.map(|r| format!("{}p", r)),
+            "ext" => self.ext.clone(),
+            "extra_type" => self.extra_type.as_deref().map(sanitise),
+            "extra_name" => self.extra_name.as_deref().map(sanitise),
+            _ => None,
+        };
+        value.filter(|v| !v.is_empty())
+    }
+}
+
+// Media server the library is named for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    #[default]
+    Plex,
+    Jellyfin,
+    Emby,
+    Kodi,
+}
+
+impl Profile {
+    // Default templates of the profile
+    pub fn templates(self) -> Templates {
+        // Name of the movie or show folder, file names of movies start with it as well
+        let (item, edition, episode) = match self {
+            Profile::Plex => ("{title} <({year}) >{{tmdb-{tmdb_id}}}", " {{edition-{edition}}}", " - S{season:02}E{episode:02}"),
+            Profile::Jellyfin => ("{title} <({year}) >[tmdbid-{tmdb_id}]", " - {edition}", " S{season:02}E{episode:02}"),
+            Profile::Emby => ("{title} <({year}) >[tmdbid={tmdb_id}]", " - {edition}", " S{season:02}E{episode:02}"),
+            Profile::Kodi => ("{title}< ({year})>", " - {edition}", " S{season:02}E{episode:02}"),
+        };
+        // Kodi has one folder for all extras
+        let extra = if self == Profile::Kodi { "Extras" } else { "{extra_type}" };
+
+        let template = |t: String| Template::parse(&t).unwrap();
+        Templates {
+            movie: template(format!("Movies/{0}/{0}<{1}>.{{ext}}", item, edition)),
+            movie_extra: template(format!("Movies/{0}/{1}/{{extra_name}}.{{ext}}", item, extra)),
+            movie_subtitle: template(format!("Movies/{0}/{0}<.{{lang}}><.{{forced}}>.{{ext}}", item)),
+            episode: template(format!("TV Shows/{0}/Season {{season:02}}/{{title}}{1}.{{ext}}", item, episode)),
+            episode_subtitle: template(format!("TV Shows/{0}/Season {{season:02}}/{{title}}{1}<.{{lang}}><.{{forced}}>.{{ext}}", item, episode)),
+        }
+    }
+
+    // Name of the folder for an extra of the given Plex extra type
+    pub fn extra_folder(self, extra_type: &str) -> String {
+        match self {
+            // Emby only knows lowercase folder names and calls other extras just extras
+            Profile::Emby if extra_type == "Other" => "extras".to_string(),
+            Profile::Emby => extra_type.to_lowercase(),
+            _ => extra_type.to_string(),
+        }
+    }
+}
+
+// Templates overriding the ones of the profile, everything not set here is taken from the profile
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Naming {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub movie: Option<Template>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub movie_extra: Option<Template>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub movie_subtitle: Option<Template>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub episode: Option<Template>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub episode_subtitle: Option<Template>,
+}
+
+impl Naming {
+    // Templates actually used, the ones of the profile with the overrides applied
+    pub fn templates(&self, profile: Profile) -> Templates {
+        let defaults = profile.templates();
+        Templates {
+            movie: self.movie.clone().unwrap_or(defaults.movie),
+            movie_extra: self.movie_extra.clone().unwrap_or(defaults.movie_extra),
+            movie_subtitle: self.movie_subtitle.clone().unwrap_or(defaults.movie_subtitle),
+            episode: self.episode.clone().unwrap_or(defaults.episode),
+            episode_subtitle: self.episode_subtitle.clone().unwrap_or(defaults.episode_subtitle),
+        }
+    }
+}
+
+// Templates for all paths created in the library, relative to the library root
+#[derive(Clone, Debug)]
+pub struct Templates {
+    pub movie: Template,
+    pub movie_extra: Template,
+    pub movie_subtitle: Template,
+    pub episode: Template,
+    pub episode_subtitle: Template,
+}
+
+impl Default for Templates {
+    fn default() -> Templates {
+        Profile::default().templates()
+    }
+}
+
+impl Templates {
+    // Check every template only uses placeholders that have a value for its kind of file
+    pub fn validate(&self) -> Result<(), String> {
+        let with = |extra: &[&'static str]| [&COMMON[..], extra].concat();
+        self.movie.check("movie", &with(&["edition"]))?;
+        self.movie_extra.check("movie_extra", &with(&["extra_type", "extra_name"]))?;
+        self.movie_subtitle.check("movie_subtitle", &with(&["lang", "forced"]))?;
+        self.episode.check("episode", &with(&["season", "episode", "episode_title"]))?;
+        self.episode_subtitle.check("episode_subtitle", &with(&["season", "episode", "episode_title", "lang", "forced"]))?;
+        Ok(())
+    }
+}
+
+// Folder of the movie or show a file belongs to: the outermost folder in the library named after it, or the folder of the file if there is none
+pub fn item_dir(to: &Path, fields: &Fields, cfg: &Config) -> PathBuf {
+    let title = sanitise(fields.title.as_deref().unwrap_or_default());
+    let parent = to.parent().unwrap_or(&cfg.plex_library);
+    let relative = parent.strip_prefix(&cfg.plex_library).unwrap_or(Path::new(""));
+
+    let mut dir = cfg.plex_library.clone();
+    for component in relative.iter() {
+        dir.push(component);
+        if !title.is_empty() && component.to_str().unwrap_or_default().contains(&title) {
+            return dir;
+        }
+    }
+    parent.to_path_buf()
+}
+
+// Add a move of a file to the path the template gives for it, or report the file if the template can not be filled in
+pub fn push<'a>(moves: &'a mut Vec<Move>, from: PathBuf, template: &Template, fields: &Fields, cfg: &Config) -> Option<&'a mut Move> {
+    match template.path(fields, cfg) {
+        Some(to) => {
+            moves.push(Move { from, to, sidecars: Vec::new() });
+            moves.last_mut()
+        },
+        None => {
+            error!("Can not name {:#?} with template {:#?}, a placeholder has no value", from, template.to_string());
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn episode() -> Fields {
+        Fields { title: Some("The Wire".to_string()), season: Some(1), episode: Some(2), ext: Some("mkv".to_string()), ..Fields::default() }
+    }
+
+    #[test]
+    fn optional_groups() {
+        let template = Template::parse("{title}< ({year})>< - {episode_title}>.{ext}").unwrap();
+        assert_eq!(template.render(&episode()).as_deref(), Some("The Wire.mkv"));
+        let fields = Fields { year: Some("2002".to_string()), episode_title: Some("The Detail".to_string()), ..episode() };
+        assert_eq!(template.render(&fields).as_deref(), Some("The Wire (2002) - The Detail.mkv"));
+        // Placeholders outside of groups are required
+        assert_eq!(Template::parse("{title} ({year}).{ext}").unwrap().render(&episode()), None);
+    }
+
+    #[test]
+    fn escapes_and_padding() {
+        let template = Template::parse("{title} {{tmdb-{tmdb_id}}} S{season:02}E{episode:03}.{ext}").unwrap();
+        let fields = Fields { tmdb_id: Some(1438), ..episode() };
+        assert_eq!(template.render(&fields).as_deref(), Some("The Wire {tmdb-1438} S01E002.mkv"));
+        assert_eq!(Template::parse("{title:2}").unwrap().render(&episode()).as_deref(), Some("The Wire"));
+        assert_eq!(Template::parse("{{}}").unwrap().render(&Fields::default()).as_deref(), Some("{}"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Template::parse("{title").is_err());
+        assert!(Template::parse("{title}}").is_err());
+        assert!(Template::parse("{unknown}").is_err());
+        assert!(Template::parse("{season:xx}").is_err());
+        assert!(Template::parse("<{year}").is_err());
+        assert!(Template::parse("{year}>").is_err());
+    }
+
+    #[test]
+    fn validation() {
+        assert!(Templates::default().validate().is_ok());
+        assert!(Profile::Kodi.templates().validate().is_ok());
+        let with = |movie: &str| Templates { movie: Template::parse(movie).unwrap(), ..Templates::default() }.validate();
+        assert!(with("Movies/{title}/{title}.{ext}").is_ok());
+        assert!(with("Movies/{title}/{title}").is_err());
+        assert!(with("Movies/{title}/{title} S{season:02}.{ext}").is_err());
+        assert!(with("/Movies/{title}.{ext}").is_err());
+        assert!(with("../Movies/{title}.{ext}").is_err());
+        assert!(with("Movies/{title}/../../{title}.{ext}").is_err());
+        assert!(with("Movies/{title}..{ext}").is_ok());
+    }
+}
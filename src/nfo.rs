@@ -0,0 +1,71 @@
+use std::{fs, path::Path};
+
+use regex::Regex;
+
+use crate::{config::Config, media::{Sidecar, Contents}, naming::{self, Fields}};
+
+// Kodi/Jellyfin compatible NFO for a movie, as "movie.nfo" in the movie folder
+pub fn movie(to: &Path, fields: &Fields, plot: Option<&str>, cfg: &Config) -> Sidecar {
+    let mut xml = String::new();
+    tag(&mut xml, "title", fields.title.as_deref());
+    tag(&mut xml, "year", fields.year.as_deref());
+    tag(&mut xml, "plot", plot);
+    ids(&mut xml, fields.tmdb_id, fields.imdb_id.as_deref());
+    Sidecar { path: naming::item_dir(to, fields, cfg).join("movie.nfo"), contents: Contents::Text(document("movie", &xml)), item: true }
+}
+
+// NFO for a show, as "tvshow.nfo" in the show folder
+pub fn tvshow(to: &Path, fields: &Fields, plot: Option<&str>, cfg: &Config) -> Sidecar {
+    let mut xml = String::new();
+    tag(&mut xml, "title", fields.title.as_deref());
+    tag(&mut xml, "year", fields.year.as_deref());
+    tag(&mut xml, "plot", plot);
+    ids(&mut xml, fields.tmdb_id, fields.imdb_id.as_deref());
+    Sidecar { path: naming::item_dir(to, fields, cfg).join("tvshow.nfo"), contents: Contents::Text(document("tvshow", &xml)), item: true }
+}
+
+// NFO for an episode, next to the episode with the same name
+pub fn episode(to: &Path, fields: &Fields, episode_id: Option<i32>, plot: Option<&str>, aired: Option<&str>) -> Sidecar {
+    let mut xml = String::new();
+    tag(&mut xml, "title", fields.episode_title.as_deref());
+    tag(&mut xml, "showtitle", fields.title.as_deref());
+    tag(&mut xml, "season", fields.season.map(|s| s.to_string()).as_deref());
+    tag(&mut xml, "episode", fields.episode.map(|e| e.to_string()).as_deref());
+    tag(&mut xml, "plot", plot);
+    tag(&mut xml, "aired", aired);
+    ids(&mut xml, episode_id, None);
+    Sidecar { path: to.with_extension("nfo"), contents: Contents::Text(document("episodedetails", &xml)), item: false }
+}
+
+// TMDB id in the "movie.nfo" or "tvshow.nfo" of a movie or show folder, as written by this tool and Kodi
+pub fn tmdb_id(dir: &Path) -> Option<i32> {
+    let id = Regex::new(r#"<uniqueid type="tmdb"[^>]*>\s*(\d+)\s*</uniqueid>|<tmdbid>\s*(\d+)\s*</tmdbid>"#).unwrap();
+    ["movie.nfo", "tvshow.nfo"].iter().find_map(|name| {
+        let xml = fs::read_to_string(dir.join(name)).ok()?;
+        let caps = id.captures(&xml)?;
+        caps.get(1).or(caps.get(2))?.as_str().parse().ok()
+    })
+}
+
+fn document(root: &str, xml: &str) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\" ?>\n<{0}>\n{1}</{0}>\n", root, xml)
+}
+
+fn tag(xml: &mut String, name: &str, value: Option<&str>) {
+    if let Some(value) = value.filter(|v| !v.is_empty()) {
+        xml.push_str(&format!("  <{0}>{1}</{0}>\n", name, escape(value)));
+    }
+}
+
+fn ids(xml: &mut String, tmdb_id: Option<i32>, imdb_id: Option<&str>) {
+    if let Some(id) = tmdb_id {
+        xml.push_str(&format!("  <uniqueid type=\"tmdb\" default=\"true\">{}</uniqueid>\n", id));
+    }
+    if let Some(id) = imdb_id {
+        xml.push_str(&format!("  <uniqueid type=\"imdb\">{}</uniqueid>\n", escape(id)));
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
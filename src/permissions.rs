@@ -0,0 +1,206 @@
+use std::{fs::{self, Metadata}, io, path::{Path, PathBuf}};
+
+use log::warn;
+use serde::{Serialize, Deserialize};
+
+use crate::{config::Config, transfer::Mode};
+
+// Who files and directories created in the library belong to
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Owner {
+    // Whoever runs the tool (moved files keep their owner)
+    #[default]
+    Default,
+    // Owner of the source file
+    Preserve,
+    // Owner of the library root
+    Inherit,
+    // Explicit user and/or group id
+    Set { uid: Option<u32>, gid: Option<u32> },
+}
+
+// Permission bits of files or directories created in the library
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    // Whatever the transfer leaves (copies get the mode of the source, new directories the umask)
+    #[default]
+    Default,
+    // Mode of the source file, or the directory it is in for directories
+    Preserve,
+    // Mode of the library root, without execute bits for files
+    Inherit,
+    // Explicit octal mode, e.g. "0644"
+    Set(String),
+}
+
+// Modification and access times of files and directories created in the library
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Timestamps {
+    // Whatever the transfer leaves (copies get the current time, moved files keep theirs)
+    #[default]
+    Default,
+    // Times of the source file, or the directory it is in for directories
+    Preserve,
+}
+
+// Ownership, mode and timestamp handling for everything created in the library
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Permissions {
+    #[serde(default)]
+    pub owner: Owner,
+    #[serde(default)]
+    pub file_mode: Access,
+    #[serde(default)]
+    pub dir_mode: Access,
+    #[serde(default)]
+    pub timestamps: Timestamps,
+}
+
+impl Permissions {
+    // Check explicit modes, so a typo fails at load and not halfway through a run
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, access) in [("file_mode", &self.file_mode), ("dir_mode", &self.dir_mode)] {
+            if let Access::Set(mode) = access {
+                parse_mode(mode).ok_or(format!("Invalid {} {:?}, expected an octal mode like \"0644\"", name, mode))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_mode(mode: &str) -> Option<u32> {
+    u32::from_str_radix(mode, 8).ok().filter(|m| *m <= 0o7777)
+}
+
+// Create a directory and all missing parents, applying the directory policy to every directory that did not exist before and returning them
+pub fn create_dirs(dir: &Path, source_dir: &Path, cfg: &Config) -> io::Result<Vec<PathBuf>> {
+    let missing: Vec<PathBuf> = dir.ancestors().take_while(|a| fs::symlink_metadata(a).is_err()).map(Path::to_path_buf).collect();
+    fs::create_dir_all(dir)?;
+
+    for created in missing.iter().rev() {
+        apply(created, fs::metadata(source_dir).ok().as_ref(), &cfg.permissions.dir_mode, false, cfg);
+    }
+    Ok(missing)
+}
+
+// Set the times of directories created in the library to those of their source directories, `created` holding the metadata of the source
+// directory from before the transfer; done once everything is in them, as adding files changes the modification time of a directory
+pub fn apply_dir_times(created: &[(PathBuf, Metadata)], cfg: &Config) {
+    if cfg.permissions.timestamps != Timestamps::Preserve {
+        return;
+    }
+    for (dir, source) in created {
+        if let Err(e) = set_times(dir, source) {
+            warn!("Can not set timestamps of {:#?}: {:#?}", dir, e);
+        }
+    }
+}
+
+// Apply the policy to a file that has just been transferred, `source` being the metadata of the source from before the transfer
+// Hardlinks share their inode with the source, so changing them would change the source as well; symlinks only get their owner changed
+pub fn apply_file(path: &Path, source: &Metadata, mode: Mode, cfg: &Config) {
+    match mode {
+        Mode::Hardlink => (),
+        Mode::Symlink | Mode::RelativeSymlink => set_owner(path, Some(source), true, cfg),
+        Mode::Copy | Mode::Move | Mode::Reflink => {
+            // Times first, the file might not be writable anymore after its mode is changed
+            if cfg.permissions.timestamps == Timestamps::Preserve {
+                if let Err(e) = set_times(path, source) {
+                    warn!("Can not set timestamps of {:#?}: {:#?}", path, e);
+                }
+            }
+            apply(path, Some(source), &cfg.permissions.file_mode, true, cfg);
+        },
+    }
+}
+
+// Apply the policy to a file created by the tool itself (e.g. an NFO file), which has no source to preserve anything from
+pub fn apply_created(path: &Path, cfg: &Config) {
+    apply(path, None, &cfg.permissions.file_mode, true, cfg);
+}
+
+// Apply owner and mode policy to a file or directory
+fn apply(path: &Path, source: Option<&Metadata>, access: &Access, file: bool, cfg: &Config) {
+    set_owner(path, source, false, cfg);
+
+    let mode = match access {
+        Access::Default => None,
+        Access::Preserve => source.map(mode_of),
+        Access::Inherit => fs::metadata(&cfg.plex_library).ok().map(|m| if file { mode_of(&m) & 0o666 } else { mode_of(&m) }),
+        Access::Set(mode) => parse_mode(mode),
+    };
+    if let Some(mode) = mode {
+        if let Err(e) = set_mode(path, mode) {
+            warn!("Can not set mode {:o} of {:#?}: {:#?}", mode, path, e);
+        }
+    }
+}
+
+// Setting times needs ownership rather than write access, so read-only copies and directories are opened for reading
+#[cfg(unix)]
+fn set_times(path: &Path, source: &Metadata) -> io::Result<()> {
+    let times = fs::FileTimes::new().set_accessed(source.accessed()?).set_modified(source.modified()?);
+    fs::File::open(path)?.set_times(times)
+}
+
+#[cfg(not(unix))]
+fn set_times(path: &Path, source: &Metadata) -> io::Result<()> {
+    let times = fs::FileTimes::new().set_accessed(source.accessed()?).set_modified(source.modified()?);
+    fs::File::options().write(true).open(path)?.set_times(times)
+}
+
+#[cfg(unix)]
+fn set_owner(path: &Path, source: Option<&Metadata>, link: bool, cfg: &Config) {
+    use std::os::unix::fs::{chown, lchown, MetadataExt};
+
+    let (uid, gid) = match &cfg.permissions.owner {
+        Owner::Default => return,
+        Owner::Preserve => match source {
+            Some(source) => (Some(source.uid()), Some(source.gid())),
+            None => return,
+        },
+        Owner::Inherit => match fs::metadata(&cfg.plex_library) {
+            Ok(root) => (Some(root.uid()), Some(root.gid())),
+            Err(e) => {
+                warn!("Can not read owner of the library root: {:#?}", e);
+                return;
+            }
+        },
+        Owner::Set { uid, gid } => (*uid, *gid),
+    };
+
+    let result = if link { lchown(path, uid, gid) } else { chown(path, uid, gid) };
+    if let Err(e) = result {
+        warn!("Can not change owner of {:#?}: {:#?}", path, e);
+    }
+}
+
+#[cfg(not(unix))]
+fn set_owner(_path: &Path, _source: Option<&Metadata>, _link: bool, _cfg: &Config) {}
+
+#[cfg(unix)]
+fn mode_of(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &Metadata) -> u32 {
+    0
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
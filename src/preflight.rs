@@ -0,0 +1,100 @@
+use std::{fs, path::{Path, PathBuf}, process};
+
+use indicatif::HumanBytes;
+use log::warn;
+
+use crate::{config::Config, journal, media::Move, transfer::{self, Mode, LinkFallback}};
+
+// Check that there is enough free space for all moves and that every directory involved is writable, returns everything that is wrong
+pub fn check(moves: &[Move], mode: Mode, cfg: &Config) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+
+    // A library that is not there (e.g. an unmounted share) would otherwise get created on the local disk
+    if !cfg.plex_library.is_dir() {
+        problems.push(format!("Plex library {:#?} does not exist or is not a directory", cfg.plex_library));
+        return problems;
+    }
+
+    // Bytes needed per destination file system, identified by the closest existing directory of the first file going there
+    let mut needed: Vec<(Option<u64>, PathBuf, u64)> = Vec::new();
+    for move_file in moves {
+        let bytes = bytes_needed(move_file, mode, cfg);
+        let device = transfer::device(&move_file.to);
+        match needed.iter_mut().find(|(d, _, _)| device.is_some() && *d == device) {
+            Some((_, _, total)) => *total += bytes,
+            None => needed.push((device, existing_ancestor(&move_file.to), bytes)),
+        }
+    }
+    for (_, dir, bytes) in needed {
+        match fs4::available_space(&dir) {
+            Ok(available) if available < bytes => problems.push(format!("Not enough space on the file system of {:#?}: {} needed, {} available", dir, HumanBytes(bytes), HumanBytes(available))),
+            Ok(_) => (),
+            Err(e) => problems.push(format!("Can not find out free space on the file system of {:#?}: {}", dir, e)),
+        }
+    }
+
+    // Every directory files get created in, and for moves every directory files get removed from, has to be writable
+    let mut dirs: Vec<PathBuf> = moves.iter().map(|m| existing_ancestor(&m.to)).collect();
+    if mode == Mode::Move {
+        dirs.extend(moves.iter().map(|m| existing_ancestor(&m.from)));
+    }
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        if let Err(e) = probe_writable(&dir) {
+            problems.push(format!("No write permission for {:#?}: {}", dir, e));
+        }
+    }
+
+    problems
+}
+
+// Run the checks, and end the program with a report of everything that is wrong
+pub fn check_or_exit(moves: &[Move], mode: Mode, cfg: &Config) {
+    // Not a problem, but overwritten files take up space until they get pruned
+    let backups = journal::backups(cfg);
+    if !backups.is_empty() {
+        let size = backups.iter().map(|(_, _, size)| size).sum();
+        warn!("{} backup(s) of overwritten files take up {}, remove them with `undo --prune`", backups.len(), HumanBytes(size));
+    }
+
+    let problems = check(moves, mode, cfg);
+    if problems.is_empty() {
+        return;
+    }
+    eprintln!("Pre-flight checks failed, nothing has been transferred:");
+    for problem in problems {
+        eprintln!("  {}", problem);
+    }
+    process::exit(1);
+}
+
+// Bytes a move takes up on the destination file system
+fn bytes_needed(move_file: &Move, mode: Mode, cfg: &Config) -> u64 {
+    let size = fs::metadata(&move_file.from).map(|m| m.len()).unwrap_or_default();
+    let same_device = transfer::same_device(&move_file.from, &move_file.to) == Some(true);
+    let bytes = match mode {
+        Mode::Copy => size,
+        Mode::Symlink | Mode::RelativeSymlink => 0,
+        Mode::Move | Mode::Hardlink | Mode::Reflink if same_device => 0,
+        Mode::Move => size,
+        Mode::Hardlink | Mode::Reflink if cfg.link_fallback == LinkFallback::Copy => size,
+        Mode::Hardlink | Mode::Reflink => 0,
+    };
+
+    // Partial copies of an interrupted run get resumed
+    let part = fs::metadata(transfer::part_path(&move_file.to)).map(|m| m.len()).unwrap_or_default();
+    bytes.saturating_sub(part)
+}
+
+// Closest directory of a path (or the path itself) that exists already
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors().skip(1).find(|a| a.is_dir()).unwrap_or(Path::new(".")).to_path_buf()
+}
+
+// Check if a directory is writable by creating and removing a file in it, permission bits do not tell the whole story on network shares
+fn probe_writable(dir: &Path) -> std::io::Result<()> {
+    let probe = dir.join(format!(".plex-media-ingest-probe-{}", process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(probe)
+}
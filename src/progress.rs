@@ -0,0 +1,84 @@
+use std::{path::Path, time::Instant, sync::atomic::{AtomicU64, Ordering}};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, HumanBytes, HumanDuration};
+
+// Progress bars for a whole run, one for every file being copied and one for the whole run, hidden when disabled
+pub struct Progress {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    enabled: bool,
+    start: Instant,
+    // Bytes of all files handled completely, and bytes copied so far of files being copied right now
+    done: AtomicU64,
+    copying: AtomicU64,
+}
+
+impl Progress {
+    pub fn new(total: u64, enabled: bool) -> Progress {
+        let target = if enabled { ProgressDrawTarget::stderr() } else { ProgressDrawTarget::hidden() };
+        let multi = MultiProgress::with_draw_target(target);
+        let overall = multi.add(ProgressBar::new(total));
+        overall.set_style(ProgressStyle::with_template("{prefix:>9.bold} [{wide_bar:.green/white}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}")
+            .unwrap()
+            .progress_chars("=> "));
+        overall.set_prefix("Total");
+        Progress { multi, overall, enabled, start: Instant::now(), done: AtomicU64::new(0), copying: AtomicU64::new(0) }
+    }
+
+    // Add a bar for a single file
+    pub fn file(&self, path: &Path, size: u64) -> ProgressBar {
+        let bar = self.multi.insert_before(&self.overall, ProgressBar::new(size));
+        bar.set_style(ProgressStyle::with_template("{prefix:>9.bold} [{bar:30.cyan/white}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta} {msg}")
+            .unwrap()
+            .progress_chars("=> "));
+        bar.set_prefix("Copying");
+        bar.set_message(path.file_name().unwrap_or_default().to_string_lossy().to_string());
+        bar
+    }
+
+    // Account for bytes copied of a file being copied right now
+    pub fn inc(&self, bytes: u64) {
+        self.copying.fetch_add(bytes, Ordering::Relaxed);
+        self.update();
+    }
+
+    // Remove the bar of a file and the bytes accounted for by `inc`, they get accounted for by `add_done` when the file has been handled
+    pub fn remove(&self, bar: &ProgressBar, copied: u64) {
+        bar.finish_and_clear();
+        self.copying.fetch_sub(copied, Ordering::Relaxed);
+        self.update();
+    }
+
+    // Account for a file that has been handled completely
+    pub fn add_done(&self, bytes: u64) {
+        self.done.fetch_add(bytes, Ordering::Relaxed);
+        self.update();
+    }
+
+    // Update the overall bar from the files handled so far and the files being copied
+    fn update(&self) {
+        self.overall.set_position(self.done.load(Ordering::Relaxed) + self.copying.load(Ordering::Relaxed));
+    }
+
+    // Print a line above the bars
+    pub fn println(&self, line: String) {
+        if self.enabled {
+            _ = self.multi.println(line);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    // Remove the bars
+    pub fn clear(&self) {
+        self.overall.finish_and_clear();
+    }
+
+    // Remove the bars and print a summary of the run
+    pub fn finish(&self, done: usize, failed: usize, skipped: usize, bytes: u64) {
+        self.clear();
+        let elapsed = self.start.elapsed();
+        let throughput = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        println!("{} transferred ({}), {} failed, {} skipped in {} ({}/s)", done, HumanBytes(bytes), failed, skipped, HumanDuration(elapsed), HumanBytes(throughput as u64));
+    }
+}
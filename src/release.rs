@@ -0,0 +1,476 @@
+use std::{collections::HashMap, path::Path, sync::{Mutex, OnceLock}, time::SystemTime};
+
+use log::trace;
+use regex::{Captures, Regex};
+
+// Fields parsed from a release name, e.g. "Dune.Part.Two.2024.2160p.AMZN.WEB-DL.DDP5.1.DV.HDR10.H.265-GROUP"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Release {
+    pub title: String,
+    pub year: Option<i32>,
+    pub season: Option<i32>,
+    pub episode: Option<i32>,
+    // Vertical resolution, e.g. 1080 for "1080p" and 2160 for "4K"
+    pub resolution: Option<u32>,
+    pub source: Option<String>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    pub hdr: Option<String>,
+    pub edition: Option<String>,
+    pub group: Option<String>,
+    // ISO-639-1 codes of languages named in the release, e.g. "de" for "GERMAN"
+    pub languages: Vec<String>,
+    // Ids the name is tagged with, e.g. "{tmdb-27205}", "{imdb-tt1375666}" or "[tvdbid-81189]"
+    pub tmdb_id: Option<i32>,
+    pub imdb_id: Option<String>,
+    pub tvdb_id: Option<i32>,
+}
+
+// Extensions stripped from file names before parsing
+const EXTENSIONS: [&str; 21] = ["mkv", "mp4", "m4v", "avi", "mov", "wmv", "mpg", "mpeg", "ts", "m2ts", "webm", "flv", "iso", "srt", "ass", "ssa", "sub", "idx", "smi", "vob", "nfo"];
+
+// Tokens with their canonical names, the first matching pattern of a list wins
+const SOURCES: [(&str, &str); 9] = [
+    (r"blu-?ray|bd-?rip|br-?rip|bd-?remux|bdmv", "BluRay"),
+    (r"web-?dl", "WEB-DL"),
+    (r"web-?rip", "WEBRip"),
+    (r"web", "WEB"),
+    (r"hdtv", "HDTV"),
+    (r"pdtv|sdtv|tv-?rip", "TV"),
+    (r"dvd-?rip|dvd-?r|dvd[59]?", "DVD"),
+    (r"hd-?rip", "HDRip"),
+    (r"cam-?rip|hdcam|telesync|hdts", "CAM"),
+];
+const VIDEO_CODECS: [(&str, &str); 8] = [
+    (r"[xh][ .]?265|hevc", "H.265"),
+    (r"[xh][ .]?264|avc", "H.264"),
+    (r"av1", "AV1"),
+    (r"vp9", "VP9"),
+    (r"xvid", "XviD"),
+    (r"divx", "DivX"),
+    (r"mpeg-?2", "MPEG-2"),
+    (r"vc-?1", "VC-1"),
+];
+// Audio codecs may be followed by the channel layout, e.g. "DDP5.1"
+const AUDIO_CODECS: [(&str, &str); 12] = [
+    (r"true-?hd(?:[ .]?\d[ .]\d)?", "TrueHD"),
+    (r"dts-?hd[ .-]?ma(?:[ .]?\d[ .]\d)?", "DTS-HD MA"),
+    (r"dts-?x", "DTS:X"),
+    (r"dts-?hd(?:[ .]?\d[ .]\d)?", "DTS-HD"),
+    (r"dts(?:[ .]?\d[ .]\d)?", "DTS"),
+    (r"(?:ddp|dd\+|e-?ac-?3)(?:[ .]?\d[ .]\d)?", "E-AC-3"),
+    (r"(?:dd|ac-?3)(?:[ .]?\d[ .]\d)?", "AC-3"),
+    (r"aac(?:[ .]?\d[ .]\d)?", "AAC"),
+    (r"flac(?:[ .]?\d[ .]\d)?", "FLAC"),
+    (r"opus(?:[ .]?\d[ .]\d)?", "Opus"),
+    (r"mp3", "MP3"),
+    (r"l?pcm(?:[ .]?\d[ .]\d)?", "PCM"),
+];
+const HDR_FORMATS: [(&str, &str); 5] = [
+    (r"dv|dovi|dolby[ .-]?vision", "Dolby Vision"),
+    (r"hdr10(?:\+|plus)", "HDR10+"),
+    (r"hdr10", "HDR10"),
+    (r"hlg", "HLG"),
+    (r"hdr", "HDR"),
+];
+const EDITIONS: [(&str, &str); 12] = [
+    (r"extended(?:[ .-](?:cut|edition))?", "Extended"),
+    (r"director'?s[ .-]?cut", "Director's Cut"),
+    (r"theatrical(?:[ .-](?:cut|edition))?", "Theatrical"),
+    (r"final[ .-]cut", "Final Cut"),
+    (r"ultimate[ .-](?:cut|edition)", "Ultimate Edition"),
+    (r"special[ .-]edition", "Special Edition"),
+    (r"collector'?s[ .-]edition", "Collector's Edition"),
+    (r"anniversary[ .-]edition", "Anniversary Edition"),
+    (r"unrated", "Unrated"),
+    (r"uncut", "Uncut"),
+    (r"remaster(?:ed)?", "Remastered"),
+    (r"imax", "IMAX"),
+];
+const LANGUAGES: [(&str, &str); 17] = [
+    (r"english|eng", "en"),
+    (r"german|deutsch|ger", "de"),
+    (r"french|truefrench|vff|vfq", "fr"),
+    (r"italian|ita", "it"),
+    (r"spanish|castellano|latino|esp|spa", "es"),
+    (r"portuguese|por", "pt"),
+    (r"dutch|nld", "nl"),
+    (r"swedish|swe", "sv"),
+    (r"danish|dan", "da"),
+    (r"norwegian|nor", "no"),
+    (r"finnish|fin", "fi"),
+    (r"polish|pol", "pl"),
+    (r"russian|rus", "ru"),
+    (r"japanese|jpn", "ja"),
+    (r"korean|kor", "ko"),
+    (r"chinese|chi", "zh"),
+    (r"hindi|hin", "hi"),
+];
+// Everything else release names are known to contain, only used to tell where the title ends
+const OTHER: &str = r"repack\d?|proper|real|rerip|internal|limited|complete|multi|dual|dl|dubbed|subbed|hybrid|remux|atmos|\d{1,2}bit|hfr|3d|sdr|amzn|nf|dsnp|hmax|atvp|hulu|pcok|pmtp|stan|crav|itunes|vudu";
+// Bare tokens that are also words in titles (e.g. "Charlotte's Web", "Mr. Holland's Opus"), only taken after the year or another token
+const AMBIGUOUS: &str = r"(?i)^(?:web|opus|dd|dv|hdr|hlg|avc|dvd)$";
+
+// Search queries tried at most for a release
+const MAX_QUERIES: usize = 8;
+
+impl Release {
+    // Search queries (title and year) to try in order until TMDB finds something: the title with and without the year,
+    // then with "&"/"and" swapped, diacritics folded, the subtitle or parts in brackets dropped, and alternative titles
+    pub fn queries(&self) -> Vec<(String, Option<i32>)> {
+        let title = self.title.as_str();
+        let swapped = swap_and(title);
+        let mut titles = vec![title.to_string(), swapped.clone(), fold_diacritics(title), fold_diacritics(&swapped)];
+        // Last subtitle after a colon or dash, e.g. "Mission: Impossible - Dead Reckoning", unless only one word would be left
+        let subtitle = regex(r"\s*:\s|\s+-\s+").find_iter(title).last();
+        titles.extend(subtitle.map(|m| title[..m.start()].to_string()).filter(|t| t.split_whitespace().count() > 1));
+        titles.push(clean_title(&regex(r"\([^)]*\)").replace_all(title, " ")));
+        // Alternative titles, e.g. "Der Untergang AKA Downfall"
+        titles.extend(regex(r"(?i)\s+aka\s+|\s+/\s+").split(title).map(String::from));
+
+        let mut queries: Vec<(String, Option<i32>)> = Vec::new();
+        // A year the parser left in the title (e.g. "The Matrix 1999") is tried as the year right after the full title
+        let trailing = regex(r"^(.+)\s((?:19|20)\d{2})$").captures(title).filter(|_| self.year.is_none());
+        for (i, title) in titles.into_iter().filter(|t| !t.is_empty()).enumerate() {
+            let mut candidates = vec![(title.clone(), self.year), (title, None)];
+            if let Some(caps) = trailing.as_ref().filter(|_| i == 0) {
+                candidates.push((caps[1].to_string(), caps[2].parse().ok()));
+            }
+            for query in candidates {
+                if !queries.contains(&query) {
+                    queries.push(query);
+                }
+            }
+        }
+        queries.truncate(MAX_QUERIES);
+        queries
+    }
+}
+
+// Parse a file or folder name into its fields, the title being everything in front of the year or the first technical token
+pub fn parse(name: &str) -> Release {
+    let mut release = Release::default();
+    let name = strip_extension(name);
+
+    // Fansub releases put the group in front, e.g. "[SubsPlease] Show - 01 (1080p)"
+    let lead = regex(r"^\[([^\]]+)\][ ._-]*");
+    let offset = match lead.captures(name) {
+        Some(caps) => {
+            release.group = Some(caps[1].trim().to_string());
+            caps[0].len()
+        },
+        None => 0,
+    };
+    let rest = &name[offset..];
+
+    // Plex, Jellyfin and Emby id tags, they get dropped from the title along with everything else in brackets
+    for caps in regex(r"(?i)[{\[](tmdb|imdb|tvdb)(?:id)?[-=](tt\d+|\d+)[}\]]").captures_iter(rest) {
+        match caps[1].to_ascii_lowercase().as_str() {
+            "tmdb" => release.tmdb_id = caps[2].parse().ok(),
+            "imdb" => release.imdb_id = Some(caps[2].to_ascii_lowercase()),
+            _ => release.tvdb_id = caps[2].parse().ok(),
+        }
+    }
+
+    // Technical tokens can be anywhere, but the title ends at the first one
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    if let Some(caps) = find(r"(\d{3,4})[pi]|4k|uhd", rest) {
+        release.resolution = caps.get(2).map_or(Some(2160), |m| m.as_str().parse().ok());
+        spans.push(span(&caps));
+    }
+    // Multi-episode files (e.g. "S01E01E02" or "S01E01-E02") are named after their first episode
+    if let Some(caps) = find(r"s(\d{1,2})[ ._-]?e(\d{1,3})(?:-?e\d{1,3})*|(\d{1,2})x(\d{2,3})", rest) {
+        release.season = caps.get(2).or(caps.get(4)).and_then(|m| m.as_str().parse().ok());
+        release.episode = caps.get(3).or(caps.get(5)).and_then(|m| m.as_str().parse().ok());
+        spans.push(span(&caps));
+    } else if let Some(caps) = find(r"s(\d{1,2})|season[ ._-]?(\d{1,2})", rest) {
+        release.season = caps.get(2).or(caps.get(3)).and_then(|m| m.as_str().parse().ok());
+        spans.push(span(&caps));
+    } else if let Some(caps) = regex(r"\s(-\s(\d{1,4})(?:v\d)?)(?:\s|$)").captures(rest).filter(|c| !regex(r"^(?:19|20)\d{2}$").is_match(&c[2])) {
+        // Absolute episode numbers of fansub releases, e.g. "Show - 01" or "One Piece - 1071", but not years like "Show - 2019"
+        release.episode = caps[2].parse().ok();
+        spans.push(span(&caps));
+    }
+    // Daily shows are dated, e.g. "The.Daily.Show.2024.03.14", that is not the year of the show
+    if let Some(caps) = find(r"(?:19|20)\d{2}[ ._-]\d{2}[ ._-]\d{2}", rest) {
+        spans.push(span(&caps));
+    }
+    // Title words can look like tokens, so the title is anchored on the year and the first token that can not be a word
+    let ambiguous = |caps: &Captures| regex(AMBIGUOUS).is_match(&caps[1]);
+    let tables = [&SOURCES[..], &VIDEO_CODECS, &AUDIO_CODECS, &HDR_FORMATS];
+    let strong = tables.iter().flat_map(|t| t.iter()).flat_map(|(pattern, _)| find_all(pattern, rest)).filter(|c| !ambiguous(c)).map(|c| span(&c).0);
+    let technical = spans.iter().map(|s| s.0).chain(strong).min();
+
+    // The year is the last one in front of the technical tokens, but never the start of the name (titles like "1917" or "2012" exist)
+    // or in the future. A year that is the only number and comes without brackets or tokens is taken as part of the title
+    // (e.g. "Blade Runner 2049"), queries still try it as the year
+    let lone = technical.is_none() && !rest.contains(['(', '[', '{']) && rest.chars().filter(char::is_ascii_digit).count() == 4;
+    let year = find_all(r"(?:19|20)\d{2}", rest).into_iter()
+        .filter(|caps| span(caps).0 > 0 && technical.is_none_or(|t| span(caps).0 < t))
+        .filter(|caps| !lone && caps[1].parse::<i32>().is_ok_and(|y| y <= this_year() + 1))
+        .last();
+    if let Some(caps) = &year {
+        release.year = caps[1].parse().ok();
+        spans.push(span(caps));
+    }
+    let anchor = year.as_ref().map(|c| span(c).0).into_iter().chain(technical).min();
+    let title_end = anchor.unwrap_or(rest.len());
+
+    let accept = |caps: &Captures| !ambiguous(caps) || span(caps).0 >= title_end;
+    release.source = first(rest, &SOURCES, accept, &mut spans);
+    release.video_codec = first(rest, &VIDEO_CODECS, accept, &mut spans);
+    release.audio_codec = first(rest, &AUDIO_CODECS, accept, &mut spans);
+    release.hdr = first(rest, &HDR_FORMATS, accept, &mut spans);
+
+    // Words like editions and languages also appear in titles (e.g. "The Final Cut"), so they only count after the year or a technical token
+    let after_title = |caps: &Captures| anchor.is_some() && span(caps).0 >= title_end;
+    let tag = regex(r"\{edition-([^}]+)\}");
+    release.edition = match tag.captures(rest) {
+        Some(caps) => Some(caps[1].to_string()),
+        None => EDITIONS.iter().find_map(|(pattern, canonical)| {
+            let caps = find_all(pattern, rest).into_iter().find(after_title)?;
+            spans.push(span(&caps));
+            Some(canonical.to_string())
+        }),
+    };
+    for (pattern, code) in LANGUAGES {
+        for caps in find_all(pattern, rest).into_iter().filter(after_title) {
+            spans.push(span(&caps));
+            if !release.languages.iter().any(|l| l == code) {
+                release.languages.push(code.to_string());
+            }
+        }
+    }
+    spans.extend(find_all(OTHER, rest).iter().filter(|c| after_title(c)).map(span));
+
+    // Scene releases end in "-GROUP", unless that is part of a token (e.g. "WEB-DL") or the title (e.g. "Spider-Man")
+    let trailer = regex(r"-([A-Za-z0-9]+)(?:\[[^\]]*\])?$");
+    if let Some(caps) = trailer.captures(rest) {
+        let start = caps.get(1).map_or(0, |m| m.start());
+        if release.group.is_none() && start > title_end && !spans.iter().any(|(s, e)| (*s..*e).contains(&start)) {
+            release.group = Some(caps[1].to_string());
+        }
+    }
+
+    release.title = clean_title(&rest[..title_end]);
+    trace!("Parsed {:#?} into {:#?}", name, release);
+    release
+}
+
+// Releases can be dated up to a year ahead, e.g. when a film premieres at a festival
+fn this_year() -> i32 {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()[..4].parse().unwrap_or(i32::MAX)
+}
+
+fn strip_extension(name: &str) -> &str {
+    match Path::new(name).extension().and_then(|e| e.to_str()) {
+        Some(ext) if EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()) => &name[..name.len() - ext.len() - 1],
+        _ => name,
+    }
+}
+
+// All occurrences of a token (case-insensitive, delimited by anything but letters and digits), the token itself is the first group
+fn find_all<'a>(pattern: &str, s: &'a str) -> Vec<Captures<'a>> {
+    let re = regex(&format!(r"(?i)(?:^|[^a-z0-9])({})(?:[^a-z0-9]|$)", pattern));
+    let mut found = Vec::new();
+    let mut at = 0;
+    // Delimiters are part of the match, so continue right after the token to find adjacent ones
+    while let Some(caps) = re.captures_at(s, at) {
+        at = span(&caps).1;
+        found.push(caps);
+    }
+    found
+}
+
+// Compiled patterns, as every file and folder name gets parsed
+fn regex(pattern: &str) -> Regex {
+    static COMPILED: OnceLock<Mutex<HashMap<String, Regex>>> = OnceLock::new();
+    let mut compiled = COMPILED.get_or_init(Default::default).lock().unwrap();
+    compiled.entry(pattern.to_string()).or_insert_with(|| Regex::new(pattern).unwrap()).clone()
+}
+
+fn find<'a>(pattern: &str, s: &'a str) -> Option<Captures<'a>> {
+    find_all(pattern, s).into_iter().next()
+}
+
+// Canonical name of the first pattern of a list found in the name where it is accepted
+fn first(s: &str, tokens: &[(&str, &str)], accept: impl Fn(&Captures) -> bool, spans: &mut Vec<(usize, usize)>) -> Option<String> {
+    tokens.iter().find_map(|(pattern, canonical)| {
+        let caps = find_all(pattern, s).into_iter().find(|c| accept(c))?;
+        spans.push(span(&caps));
+        Some(canonical.to_string())
+    })
+}
+
+fn span(caps: &Captures) -> (usize, usize) {
+    caps.get(1).map_or((0, 0), |m| (m.start(), m.end()))
+}
+
+// Swap "&" for "and", or the other way around
+fn swap_and(title: &str) -> String {
+    if title.contains(" & ") {
+        title.replace(" & ", " and ")
+    } else {
+        regex(r"(?i)\sand\s").replace_all(title, " & ").to_string()
+    }
+}
+
+// Replace letters with diacritics by their base letters, e.g. "Amélie" by "Amelie"
+fn fold_diacritics(title: &str) -> String {
+    title.chars().map(|c| match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' | 'Ā' | 'Ă' | 'Ą' => "A",
+        'ç' | 'ć' | 'č' => "c",
+        'Ç' | 'Ć' | 'Č' => "C",
+        'ď' | 'đ' => "d",
+        'Ď' | 'Đ' => "D",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => "e",
+        'È' | 'É' | 'Ê' | 'Ë' | 'Ē' | 'Ė' | 'Ę' | 'Ě' => "E",
+        'ğ' => "g",
+        'Ğ' => "G",
+        'ì' | 'í' | 'î' | 'ï' | 'ī' | 'į' | 'ı' => "i",
+        'Ì' | 'Í' | 'Î' | 'Ï' | 'Ī' | 'Į' | 'İ' => "I",
+        'ł' | 'ľ' | 'ĺ' => "l",
+        'Ł' | 'Ľ' | 'Ĺ' => "L",
+        'ñ' | 'ń' | 'ň' => "n",
+        'Ñ' | 'Ń' | 'Ň' => "N",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => "o",
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ø' | 'Ō' | 'Ő' => "O",
+        'ŕ' | 'ř' => "r",
+        'Ŕ' | 'Ř' => "R",
+        'ś' | 'š' | 'ş' | 'ș' => "s",
+        'Ś' | 'Š' | 'Ş' | 'Ș' => "S",
+        'ť' | 'ţ' | 'ț' => "t",
+        'Ť' | 'Ţ' | 'Ț' => "T",
+        'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' | 'ų' => "u",
+        'Ù' | 'Ú' | 'Û' | 'Ü' | 'Ū' | 'Ů' | 'Ű' | 'Ų' => "U",
+        'ý' | 'ÿ' => "y",
+        'Ý' | 'Ÿ' => "Y",
+        'ź' | 'ż' | 'ž' => "z",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        'ß' => "ss",
+        'æ' => "ae",
+        'Æ' => "AE",
+        'œ' => "oe",
+        'Œ' => "OE",
+        _ => return c.to_string(),
+    }.to_string()).collect()
+}
+
+// Turn separators into spaces and drop tags and dangling brackets, e.g. "The.Matrix.(" into "The Matrix"
+fn clean_title(title: &str) -> String {
+    let tags = regex(r"\[[^\]]*\]|\{[^}]*\}|\(\s*\)");
+    let title = tags.replace_all(title, " ").replace(['.', '_'], " ");
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    title.trim_matches(|c: char| c.is_whitespace() || "-([{".contains(c)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Name, title, year, season, episode, resolution, source, group
+    type Case = (&'static str, &'static str, Option<i32>, Option<i32>, Option<i32>, Option<u32>, Option<&'static str>, Option<&'static str>);
+
+    const CORPUS: &[Case] = &[
+        // Scene
+        ("The.Matrix.1999.1080p.BluRay.x264-SPARKS.mkv", "The Matrix", Some(1999), None, None, Some(1080), Some("BluRay"), Some("SPARKS")),
+        ("Inception.2010.720p.BRRip.XviD.AC3-ViSiON.avi", "Inception", Some(2010), None, None, Some(720), Some("BluRay"), Some("ViSiON")),
+        ("Blade.Runner.2049.2017.2160p.UHD.BluRay.REMUX.HDR.HEVC.Atmos-EPSiLON", "Blade Runner 2049", Some(2017), None, None, Some(2160), Some("BluRay"), Some("EPSiLON")),
+        ("1917.2019.1080p.WEB-DL.DD5.1.H264-FGT", "1917", Some(2019), None, None, Some(1080), Some("WEB-DL"), Some("FGT")),
+        ("2012.2009.720p.BluRay.x264-METiS", "2012", Some(2009), None, None, Some(720), Some("BluRay"), Some("METiS")),
+        ("Charlotte's.Web.2006.1080p.x264-GRP", "Charlotte's Web", Some(2006), None, None, Some(1080), None, Some("GRP")),
+        ("Mr.Hollands.Opus.1995.720p.x264", "Mr Hollands Opus", Some(1995), None, None, Some(720), None, None),
+        ("Spider-Man.No.Way.Home.2021.1080p.WEBRip.x264-RARBG", "Spider-Man No Way Home", Some(2021), None, None, Some(1080), Some("WEBRip"), Some("RARBG")),
+        ("The.Office.US.S05E14.720p.HDTV.x264-CTU", "The Office US", None, Some(5), Some(14), Some(720), Some("HDTV"), Some("CTU")),
+        ("Breaking.Bad.S01.1080p.BluRay.x265-GalaxyTV", "Breaking Bad", None, Some(1), None, Some(1080), Some("BluRay"), Some("GalaxyTV")),
+        ("Der.Untergang.2004.German.DL.1080p.BluRay.x264-DETAiLS", "Der Untergang", Some(2004), None, None, Some(1080), Some("BluRay"), Some("DETAiLS")),
+        ("House.of.the.Dragon.S02E01.WEB.H264-RBB", "House of the Dragon", None, Some(2), Some(1), None, Some("WEB"), Some("RBB")),
+        // P2P
+        ("Dune.Part.Two.2024.2160p.AMZN.WEB-DL.DDP5.1.DV.HDR10.H.265-FLUX", "Dune Part Two", Some(2024), None, None, Some(2160), Some("WEB-DL"), Some("FLUX")),
+        ("Oppenheimer (2023) [1080p] [BluRay] [5.1] [YTS.MX]", "Oppenheimer", Some(2023), None, None, Some(1080), Some("BluRay"), None),
+        ("Parasite.2019.KOREAN.1080p.BluRay.DTS-HD.MA.5.1.x264-HDS", "Parasite", Some(2019), None, None, Some(1080), Some("BluRay"), Some("HDS")),
+        ("The Lord of the Rings The Fellowship of the Ring 2001 EXTENDED 1080p BluRay x265 10bit", "The Lord of the Rings The Fellowship of the Ring", Some(2001), None, None, Some(1080), Some("BluRay"), None),
+        ("Severance.S02E03.Who.Is.Alive.1080p.ATVP.WEB-DL.DDP5.1.H.264-NTb", "Severance", None, Some(2), Some(3), Some(1080), Some("WEB-DL"), Some("NTb")),
+        // Fansub
+        ("[SubsPlease] Frieren - 01 (1080p) [F02B9CEE].mkv", "Frieren", None, None, Some(1), Some(1080), None, Some("SubsPlease")),
+        ("[Erai-raws] Jujutsu Kaisen - 24 [720p][Multiple Subtitle].mkv", "Jujutsu Kaisen", None, None, Some(24), Some(720), None, Some("Erai-raws")),
+        ("[Judas] Cowboy Bebop - S01E05 [1080p][HEVC x265 10bit].mkv", "Cowboy Bebop", None, Some(1), Some(5), Some(1080), None, Some("Judas")),
+        ("[HorribleSubs] One Piece - 1071 [1080p].mkv", "One Piece", None, None, Some(1071), Some(1080), None, Some("HorribleSubs")),
+        ("[Erai-raws] Spy x Family - 12v2 [1080p][Multiple Subtitle].mkv", "Spy x Family", None, None, Some(12), Some(1080), None, Some("Erai-raws")),
+        ("[Commie] Steins;Gate - 01 [BD 720p AAC] [5AF8C3D1].mkv", "Steins;Gate", None, None, Some(1), Some(720), None, Some("Commie")),
+        ("[Anime Time] Attack on Titan - 087 [1080p HEVC 10bit].mkv", "Attack on Titan", None, None, Some(87), Some(1080), None, Some("Anime Time")),
+        ("Mushoku Tensei - 05 [1080p].mkv", "Mushoku Tensei", None, None, Some(5), Some(1080), None, None),
+        // Multi-episode
+        ("The.Simpsons.S35E01E02.1080p.WEB.h264-ETHEL", "The Simpsons", None, Some(35), Some(1), Some(1080), Some("WEB"), Some("ETHEL")),
+        ("Friends.S01E01-E02.720p.BluRay.x264-PSYCHD", "Friends", None, Some(1), Some(1), Some(720), Some("BluRay"), Some("PSYCHD")),
+        ("Doctor.Who.2005.S01E01-02.DVDRip.XviD", "Doctor Who", Some(2005), Some(1), Some(1), None, Some("DVD"), None),
+        ("Lost 1x01-1x02 Pilot.avi", "Lost", None, Some(1), Some(1), None, None, None),
+        // Daily
+        ("The.Daily.Show.2024.03.14.Jon.Stewart.720p.WEB.h264-EDITH", "The Daily Show", None, None, None, Some(720), Some("WEB"), Some("EDITH")),
+        ("Jeopardy.2023.11.02.480p.x264-mSD", "Jeopardy", None, None, None, Some(480), None, Some("mSD")),
+        ("The Tonight Show Starring Jimmy Fallon 2024 01 15 720p HDTV x264-SORNY", "The Tonight Show Starring Jimmy Fallon", None, None, None, Some(720), Some("HDTV"), Some("SORNY")),
+        // Years in titles
+        ("Blade Runner 2049", "Blade Runner 2049", None, None, None, None, None, None),
+        ("Blade.Runner.2049.mkv", "Blade Runner 2049", None, None, None, None, None, None),
+        ("Blade Runner 2049 (2017)", "Blade Runner 2049", Some(2017), None, None, None, None, None),
+        ("2001.A.Space.Odyssey.1968.1080p.BluRay.x264-AMIABLE", "2001 A Space Odyssey", Some(1968), None, None, Some(1080), Some("BluRay"), Some("AMIABLE")),
+        ("Wonder.Woman.1984.2020.1080p.HMAX.WEB-DL.DDP5.1.Atmos.x264-FLUX", "Wonder Woman 1984", Some(2020), None, None, Some(1080), Some("WEB-DL"), Some("FLUX")),
+        ("1984.1984.1080p.BluRay.x264", "1984", Some(1984), None, None, Some(1080), Some("BluRay"), None),
+        ("Futurama.2099.Special", "Futurama 2099 Special", None, None, None, None, None, None),
+        ("Some.Movie.2091.1080p.WEB-DL.x264-GRP", "Some Movie 2091", None, None, None, Some(1080), Some("WEB-DL"), Some("GRP")),
+        // Plex tagged
+        ("Inception (2010) {tmdb-27205}", "Inception", Some(2010), None, None, None, None, None),
+        ("Heat (1995) {imdb-tt0113277} {edition-Director's Cut}", "Heat", Some(1995), None, None, None, None, None),
+        ("The Wire [tvdbid-79126]", "The Wire", None, None, None, None, None, None),
+        // Plain
+        ("The Italian Job", "The Italian Job", None, None, None, None, None, None),
+        ("The English Patient", "The English Patient", None, None, None, None, None, None),
+        ("The Final Cut", "The Final Cut", None, None, None, None, None, None),
+        ("The.Real.Thing", "The Real Thing", None, None, None, None, None, None),
+        ("Charlotte's Web", "Charlotte's Web", None, None, None, None, None, None),
+        ("Amélie (2001)", "Amélie", Some(2001), None, None, None, None, None),
+        ("Season 01", "", None, Some(1), None, None, None, None),
+    ];
+
+    #[test]
+    fn corpus() {
+        for (name, title, year, season, episode, resolution, source, group) in CORPUS {
+            let release = parse(name);
+            let parsed = (release.title.as_str(), release.year, release.season, release.episode, release.resolution, release.source.as_deref(), release.group.as_deref());
+            assert_eq!(parsed, (*title, *year, *season, *episode, *resolution, *source, *group), "{}", name);
+        }
+    }
+
+    #[test]
+    fn tokens() {
+        let release = parse("Dune.Part.Two.2024.2160p.AMZN.WEB-DL.DDP5.1.DV.HDR10.H.265-FLUX");
+        assert_eq!(release.video_codec.as_deref(), Some("H.265"));
+        assert_eq!(release.audio_codec.as_deref(), Some("E-AC-3"));
+        assert_eq!(release.hdr.as_deref(), Some("Dolby Vision"));
+        let release = parse("Mr.Hollands.Opus.1995.720p.x264");
+        assert_eq!(release.audio_codec, None);
+        let release = parse("Der.Untergang.2004.German.DL.1080p.BluRay.x264-DETAiLS");
+        assert_eq!(release.languages, ["de"]);
+        let release = parse("Blade.Runner.1982.The.Final.Cut.1080p.BluRay.x264");
+        assert_eq!((release.title.as_str(), release.edition.as_deref()), ("Blade Runner", Some("Final Cut")));
+        let release = parse("Heat (1995) {imdb-tt0113277} {edition-Director's Cut}");
+        assert_eq!((release.imdb_id.as_deref(), release.edition.as_deref()), (Some("tt0113277"), Some("Director's Cut")));
+        assert_eq!(parse("Inception (2010) {tmdb-27205}").tmdb_id, Some(27205));
+        assert_eq!(parse("The Wire [tvdbid-79126]").tvdb_id, Some(79126));
+    }
+
+    #[test]
+    fn queries() {
+        let release = Release { title: "Mission: Impossible - Dead Reckoning".to_string(), year: Some(2023), ..Release::default() };
+        let titles: Vec<String> = release.queries().into_iter().map(|q| q.0).collect();
+        assert!(titles.contains(&"Mission: Impossible".to_string()));
+        assert!(!titles.contains(&"Mission".to_string()));
+        let release = Release { title: "Alien: Romulus".to_string(), ..Release::default() };
+        assert_eq!(release.queries(), [("Alien: Romulus".to_string(), None)]);
+        let release = Release { title: "Der Untergang AKA Downfall".to_string(), year: Some(2004), ..Release::default() };
+        assert!(release.queries().contains(&("Downfall".to_string(), Some(2004))));
+        let release = parse("The Matrix 1999");
+        assert_eq!(release.queries()[..2], [("The Matrix 1999".to_string(), None), ("The Matrix".to_string(), Some(1999))]);
+    }
+}
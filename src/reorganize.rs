@@ -0,0 +1,197 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use log::{info, warn, trace};
+use regex::Regex;
+use walkdir::WalkDir;
+
+use crate::{artwork, config::Config, release, journal, media::{self, Move, get_file_header}, movie, naming::{self, Fields, Template}, nfo, tmdb::{self, Kind}};
+
+// Identity of a library file, worked out from its path
+struct Identity<'a> {
+    template: &'a Template,
+    fields: Fields,
+    // TMDB kind the id belongs to
+    kind: Kind,
+    // NFO and artwork files in the movie or show folder that belong to this file
+    item_files: Vec<String>,
+}
+
+// Work out where every file in the library belongs under the current naming templates, and plan the moves to get it there
+pub fn plan(cfg: &Config) -> Vec<Move> {
+    let mut moves: Vec<Move> = Vec::new();
+    let mut imdb_ids: HashMap<(Kind, i32), Option<String>> = HashMap::new();
+    let mut episode_titles: HashMap<(i32, i32, i32), Option<String>> = HashMap::new();
+
+    for entry in WalkDir::new(&cfg.plex_library).sort_by_file_name().into_iter().flatten() {
+        // Hidden files are partial copies and backups of the transfer, symlinks are handled like files
+        if entry.file_type().is_dir() || entry.file_name().to_str().unwrap_or_default().starts_with('.') {
+            continue;
+        }
+        let file = entry.into_path();
+        let Some(mut identity) = identify(&file, cfg) else { continue; };
+        let fields = &mut identity.fields;
+
+        let id = fields.tmdb_id.unwrap_or_default();
+        if identity.template.uses("imdb_id") {
+            fields.imdb_id = imdb_ids.entry((identity.kind, id)).or_insert_with(|| tmdb::imdb_id(cfg, identity.kind, id)).clone();
+        }
+        if let (true, Some(season), Some(episode)) = (identity.template.uses("episode_title"), fields.season, fields.episode) {
+            fields.episode_title = episode_titles.entry((id, season, episode)).or_insert_with(|| tmdb::episode(cfg, id, season, episode).and_then(|e| e.name)).clone();
+        }
+
+        match identity.template.path(fields, cfg) {
+            Some(to) if to == file => trace!("{:#?} is already in place", file),
+            Some(to) => {
+                for sidecar_move in sidecar_moves(&file, &to, &identity, cfg) {
+                    if !moves.iter().any(|m| m.from == sidecar_move.from) {
+                        moves.push(sidecar_move);
+                    }
+                }
+                moves.push(Move { from: file, to, sidecars: Vec::new() });
+            },
+            None => warn!("Can not name {:#?} with template {:#?}, a placeholder has no value", file, identity.template.to_string()),
+        }
+    }
+    moves
+}
+
+// Work out the identity of a library file from its path: TMDB id, title and year of the folder (or file) tagged with the id, season and episode, edition, extra type and subtitle language
+fn identify<'a>(file: &Path, cfg: &'a Config) -> Option<Identity<'a>> {
+    let relative = file.strip_prefix(&cfg.plex_library).ok()?;
+    let subtitle = media::is_subtitle(file);
+    if !subtitle && !get_file_header(file.to_path_buf()).map(|h| infer::is_video(&h)).unwrap_or(false) {
+        info!("Not a video file nor subtitle, leaving {:#?} alone", file);
+        return None;
+    }
+
+    // The outermost path component carrying a TMDB id is the movie or show
+    let tag = Regex::new(r"\{tmdb-(\d+)\}|\[tmdbid[-=](\d+)\]").unwrap();
+    let components: Vec<&str> = relative.iter().map(|c| c.to_str().unwrap_or_default()).collect();
+    let tagged = components.iter().enumerate().find_map(|(depth, component)| {
+        let caps = tag.captures(component)?;
+        Some((depth, caps.get(1).or(caps.get(2))?.as_str().parse().ok()?, &component[..caps.get(0)?.start()]))
+    });
+    // Kodi names carry no id, its movie and show folders have one in their NFO file instead
+    let folders = components.len() - 1;
+    let from_nfo = || (0..folders).find_map(|depth| Some((depth, nfo::tmdb_id(&cfg.plex_library.join(components[..=depth].iter().collect::<PathBuf>()))?, components[depth])));
+    let Some((depth, id, named)) = tagged.or_else(from_nfo) else {
+        warn!("No TMDB id in the path of {:#?} nor in a movie.nfo or tvshow.nfo of its folders, leaving it alone", file);
+        return None;
+    };
+    let component = components[depth];
+    let (title, year) = title_and_year(named);
+    let item: PathBuf = components[..=depth].iter().collect();
+
+    let name = file.file_name().unwrap_or_default().to_str().unwrap_or_default();
+    let mut fields = Fields {
+        title: Some(title),
+        year,
+        tmdb_id: Some(id),
+        resolution: media::resolution(name),
+        ext: file.extension().map(|e| e.to_str().unwrap_or_default().to_string()),
+        ..Fields::default()
+    };
+    if subtitle {
+        (fields.lang, fields.forced) = media::subtitle_tags(file);
+    }
+
+    let templates = &cfg.templates;
+    // Extras can have numbers like episodes (e.g. "Featurette - 01"), so episodes in the library need a season in their name
+    let release = release::parse(name);
+    if let (Some(season), Some(episode)) = (release.season, release.episode) {
+        fields.season = Some(season);
+        fields.episode = Some(episode);
+        if subtitle {
+            return Some(Identity { template: &templates.episode_subtitle, fields, kind: Kind::Tv, item_files: Vec::new() });
+        }
+        let item_files = vec!["tvshow.nfo".to_string(), "poster.jpg".to_string(), "fanart.jpg".to_string(), artwork::season_poster(season)];
+        return Some(Identity { template: &templates.episode, fields, kind: Kind::Tv, item_files });
+    }
+
+    if subtitle {
+        return Some(Identity { template: &templates.movie_subtitle, fields, kind: Kind::Movie, item_files: Vec::new() });
+    }
+    if let Some(extra_type) = relative.strip_prefix(&item).ok().and_then(movie::guess_extra_type) {
+        fields.extra_type = Some(cfg.profile.extra_folder(extra_type));
+        fields.extra_name = file.file_stem().map(|s| s.to_str().unwrap_or_default().to_string());
+        return Some(Identity { template: &templates.movie_extra, fields, kind: Kind::Movie, item_files: Vec::new() });
+    }
+    // Plex tags editions, the other profiles append them to the name of the movie folder
+    let edition = Regex::new(r"\{edition-([^}]+)\}").unwrap();
+    let stem = file.file_stem().unwrap_or_default().to_str().unwrap_or_default();
+    fields.edition = match edition.captures(name) {
+        Some(caps) => Some(caps[1].to_string()),
+        None => stem.strip_prefix(component).and_then(|rest| rest.strip_prefix(" - ")).map(String::from),
+    };
+    let item_files = vec!["movie.nfo".to_string(), "poster.jpg".to_string(), "fanart.jpg".to_string()];
+    Some(Identity { template: &templates.movie, fields, kind: Kind::Movie, item_files })
+}
+
+// NFO and artwork files of a movie or episode (next to it with the same name, and in the movie or show folder) move along with it
+fn sidecar_moves(file: &Path, to: &Path, identity: &Identity, cfg: &Config) -> Vec<Move> {
+    if identity.item_files.is_empty() {
+        return Vec::new();
+    }
+    let from_dir = naming::item_dir(file, &identity.fields, cfg);
+    let to_dir = naming::item_dir(to, &identity.fields, cfg);
+    let mut files = vec![(file.with_extension("nfo"), to.with_extension("nfo"))];
+    files.extend(identity.item_files.iter().map(|name| (from_dir.join(name), to_dir.join(name))));
+
+    files.into_iter()
+        .filter(|(from, to)| from != to && from.is_file())
+        .map(|(from, to)| Move { from, to, sidecars: Vec::new() })
+        .collect()
+}
+
+// Split the part of a name in front of the TMDB id into title and year, e.g. "Inception (2010) "
+fn title_and_year(name: &str) -> (String, Option<String>) {
+    let year = Regex::new(r"^(.*?)\s*[(\[](\d{4})[)\]]\s*$").unwrap();
+    match year.captures(name) {
+        Some(caps) => (caps[1].trim().to_string(), Some(caps[2].to_string())),
+        None => (name.trim().to_string(), None),
+    }
+}
+
+// Remove the directories files have been moved out of, if they are empty now
+pub fn clean_up(moves: &[Move], cfg: &Config) {
+    for move_file in moves {
+        journal::remove_empty_parents(&move_file.from, &cfg.plex_library);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::config;
+
+    // Smallest file that is recognised as a Matroska video
+    fn video(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, [0x1a, 0x45, 0xdf, 0xa3, 0x01, 0x00, 0x00, 0x00]).unwrap();
+    }
+
+    #[test]
+    fn kodi_library() {
+        let cfg = config::scratch("reorganize-kodi");
+        let movie = cfg.plex_library.join("Movies/Inception (2010)");
+        video(&movie.join("Inception (2010).mkv"));
+        video(&movie.join("Extras/Trailer.mkv"));
+        video(&movie.join("Extras/Making Of.mkv"));
+        fs::write(movie.join("movie.nfo"), "<movie>\n  <uniqueid type=\"tmdb\" default=\"true\">27205</uniqueid>\n</movie>\n").unwrap();
+        video(&cfg.plex_library.join("Movies/Unknown (2001)/Unknown (2001).mkv"));
+
+        let mut moves: Vec<(PathBuf, PathBuf)> = plan(&cfg).into_iter()
+            .map(|m| (m.from.strip_prefix(&cfg.plex_library).unwrap().to_path_buf(), m.to.strip_prefix(&cfg.plex_library).unwrap().to_path_buf()))
+            .collect();
+        moves.sort();
+        let tagged = "Movies/Inception (2010) {tmdb-27205}";
+        assert_eq!(moves, [
+            ("Movies/Inception (2010)/Extras/Making Of.mkv".into(), format!("{}/Other/Making Of.mkv", tagged).into()),
+            ("Movies/Inception (2010)/Extras/Trailer.mkv".into(), format!("{}/Trailers/Trailer.mkv", tagged).into()),
+            ("Movies/Inception (2010)/Inception (2010).mkv".into(), format!("{0}/Inception (2010) {{tmdb-27205}}.mkv", tagged).into()),
+            ("Movies/Inception (2010)/movie.nfo".into(), format!("{}/movie.nfo", tagged).into()),
+        ]);
+    }
+}
@@ -0,0 +1,48 @@
+use std::{path::{Path, PathBuf}, fs::{self, OpenOptions}, io::Write};
+
+use log::error;
+use serde::{Serialize, Deserialize};
+
+use crate::{config::Config, media::timestamp};
+
+// Entry on the needs review list, for files that could not be handled without asking the user
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Review {
+    pub timestamp: u64,
+    pub path: PathBuf,
+    pub reason: String,
+    pub candidates: Vec<String>,
+}
+
+// Location of the needs review list, one JSON entry per line
+pub fn list_path(cfg: &Config) -> PathBuf {
+    cfg.data_dir.join("needs-review.jsonl")
+}
+
+// Append a file to the needs review list
+pub fn add(cfg: &Config, path: &Path, reason: &str, candidates: Vec<String>) {
+    let entry = Review { timestamp: timestamp(), path: path.to_path_buf(), reason: reason.to_string(), candidates };
+    let line = match serde_json::to_string(&entry) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("Can not serialise needs review entry: {:#?}", e);
+            return;
+        }
+    };
+
+    let written = fs::create_dir_all(&cfg.data_dir)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(list_path(cfg)))
+        .and_then(|mut f| writeln!(f, "{}", line));
+    if let Err(e) = written {
+        error!("Can not write to needs review list: {:#?}", e);
+    }
+}
+
+// Read all entries added to the needs review list at or after the given time
+pub fn since(cfg: &Config, time: u64) -> Vec<Review> {
+    let Ok(f) = fs::read_to_string(list_path(cfg)) else { return Vec::new(); };
+    f.lines()
+        .filter_map(|l| serde_json::from_str::<Review>(l).ok())
+        .filter(|r| r.timestamp >= time)
+        .collect()
+}
@@ -0,0 +1,107 @@
+use std::{any::Any, collections::VecDeque, panic::{self, AssertUnwindSafe}, sync::{Condvar, Mutex}, thread};
+
+// Queue of jobs and number of running jobs per group
+struct State<T> {
+    queue: VecDeque<(usize, Option<usize>, T)>,
+    running: Vec<usize>,
+}
+
+// Slot taken by a running job, released when the job is done or has panicked, so workers waiting for it do not wait forever
+struct Slot<'a, T> {
+    state: &'a Mutex<State<T>>,
+    changed: &'a Condvar,
+    group: Option<usize>,
+}
+
+impl<T> Drop for Slot<'_, T> {
+    fn drop(&mut self) {
+        if let Some(g) = self.group {
+            self.state.lock().unwrap_or_else(|e| e.into_inner()).running[g] -= 1;
+        }
+        self.changed.notify_all();
+    }
+}
+
+// Run jobs on a number of worker threads, jobs in a group (index into `limits`) only run up to the group's limit at the same time
+// Results are returned in the order of the jobs, a panicking job lets the other jobs finish and then panics the caller
+pub fn run<T, R, F>(jobs: Vec<(Option<usize>, T)>, limits: &[usize], workers: usize, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let count = jobs.len();
+    let state = Mutex::new(State {
+        queue: jobs.into_iter().enumerate().map(|(i, (group, job))| (i, group, job)).collect(),
+        running: vec![0; limits.len()],
+    });
+    let changed = Condvar::new();
+    let results: Mutex<Vec<Option<R>>> = Mutex::new((0..count).map(|_| None).collect());
+    let panicked: Mutex<Option<Box<dyn Any + Send>>> = Mutex::new(None);
+
+    thread::scope(|s| {
+        for _ in 0..workers.clamp(1, count.max(1)) {
+            s.spawn(|| loop {
+                // Take the first job whose group has a free slot, or wait for one to free up
+                let (i, group, job) = {
+                    let mut st = state.lock().unwrap();
+                    loop {
+                        if st.queue.is_empty() {
+                            return;
+                        }
+                        let free = st.queue.iter().position(|(_, group, _)| group.is_none_or(|g| st.running[g] < limits[g].max(1)));
+                        if let Some(pos) = free {
+                            let (i, group, job) = st.queue.remove(pos).unwrap();
+                            if let Some(g) = group {
+                                st.running[g] += 1;
+                            }
+                            break (i, group, job);
+                        }
+                        st = changed.wait(st).unwrap();
+                    }
+                };
+
+                let _slot = Slot { state: &state, changed: &changed, group };
+                // Keep the worker going, so the remaining jobs still run with a single worker
+                match panic::catch_unwind(AssertUnwindSafe(|| f(job))) {
+                    Ok(result) => results.lock().unwrap()[i] = Some(result),
+                    Err(payload) => _ = panicked.lock().unwrap().get_or_insert(payload),
+                }
+            });
+        }
+    });
+
+    if let Some(payload) = panicked.into_inner().unwrap() {
+        panic::resume_unwind(payload);
+    }
+    results.into_inner().unwrap().into_iter().map(|r| r.unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn jobs_after_a_panic_still_run() {
+        for workers in [1, 3] {
+            let ran = AtomicUsize::new(0);
+            let jobs = (0..6).map(|i| (Some(0), i)).collect();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| run(jobs, &[1], workers, |i| {
+                if i == 1 {
+                    panic!("job {} failed", i);
+                }
+                ran.fetch_add(1, Ordering::SeqCst);
+            })));
+            assert!(result.is_err());
+            assert_eq!(ran.load(Ordering::SeqCst), 5, "{} workers", workers);
+        }
+    }
+
+    #[test]
+    fn results_in_order() {
+        let jobs = (0..8).map(|i| (if i % 2 == 0 { Some(0) } else { None }, i)).collect();
+        assert_eq!(run(jobs, &[1], 4, |i| i * 10), [0, 10, 20, 30, 40, 50, 60, 70]);
+    }
+}
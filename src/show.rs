@@ -1,23 +1,11 @@
-use std::{fmt, fs::DirEntry, path::PathBuf, time::Duration};
+use std::{collections::{HashMap, HashSet}, fmt, fs::DirEntry, path::{Path, PathBuf}};
 
-use inquire::{Select, Text, Confirm};
 use log::{error, info, trace, debug, warn};
-use reqwest::{header::{HeaderMap, HeaderValue}, blocking::Client};
-use sanitise_file_name::sanitise;
 use serde::Deserialize;
-use urlencoding::encode;
 use walkdir::WalkDir;
 use inline_colorization::*;
-use regex::RegexBuilder;
 
-use crate::{config::Config, media::{Move, self, get_file_header}, directory::search_path};
-
-// Struct to hold the TMDB API response
-#[derive(Deserialize, Debug)]
-struct TMDBResponse {
-    results: Vec<TMDBEntry>,
-    total_results: i32
-}
+use crate::{config::Config, media::{Move, self, get_file_header}, directory::search_path, naming::{self, Fields}, nfo, artwork, review, decisions, release::{self, Release}, tmdb::{self, Kind}};
 
 // Struct to hold a show from the TMDB API response
 #[derive(Deserialize, Debug, Clone)]
@@ -26,6 +14,15 @@ struct TMDBEntry {
     name: String,
     original_language: Option<String>,
     first_air_date: Option<String>,
+    overview: Option<String>,
+    // Only looked up if a naming template or NFO files need them
+    #[serde(default)]
+    imdb_id: Option<String>,
+    #[serde(skip)]
+    episodes: HashMap<(i32, i32), Option<tmdb::Episode>>,
+    // Show (None) and seasons artwork has been looked up for
+    #[serde(skip)]
+    artwork: HashSet<Option<i32>>,
 }
 
 // Display implementation for the inquire selection dialog
@@ -39,69 +36,83 @@ impl fmt::Display for TMDBEntry {
 fn check_show_name(entry: PathBuf, cfg: Config) -> Option<TMDBEntry> {
     info!("Found folder: {:#?}", entry);
 
+    // Use the show picked for this folder on an earlier run
+    if let Some(id) = decisions::get(&cfg, &entry).and_then(|d| d.tmdb_id) {
+        if let Some(meta) = tmdb::details::<TMDBEntry>(&cfg, Kind::Tv, id) {
+            info!("Using {} picked for {:#?} before", meta, entry);
+            return Some(meta);
+        }
+    }
+
     let folder_name = entry.file_name().unwrap_or_default();
     trace!("Folder name is: {:#?}", folder_name);
 
-    let name_tokens = media::tokenize_media_name(folder_name.to_str().unwrap_or_default().to_string());
-    lookup_show(entry, name_tokens, cfg)
+    let release = release::parse(folder_name.to_str().unwrap_or_default());
+
+    // Folders named for Plex, Jellyfin or Emby already carry the id
+    if let Some(meta) = tmdb::tagged::<TMDBEntry>(&cfg, Kind::Tv, &release) {
+        info!("Using {} tagged in {:#?}", meta, entry);
+        return Some(meta);
+    }
+
+    lookup_show(entry, release, cfg)
 }
 
 // Look up show on the TMDB API
-fn lookup_show(folder_name: PathBuf, mut name_tokens: Vec<String>, cfg: Config) -> Option<TMDBEntry> {
-    if name_tokens.first().unwrap_or(&"".to_string()).eq_ignore_ascii_case("season") {
-        // Is a season folder most likely, skip useless TMDB requests
+fn lookup_show(folder_name: PathBuf, release: Release, cfg: Config) -> Option<TMDBEntry> {
+    if release.title.is_empty() {
+        // Is a season folder most likely (e.g. "Season 01"), skip useless TMDB requests
         return None;
     }
-    let mut h = HeaderMap::new();
-    h.insert("Accept", HeaderValue::from_static("application/json"));
-    h.insert("Authorization", HeaderValue::from_str(format!("Bearer {}", cfg.tmdb_key).as_str()).unwrap());
-    
-    let client = Client::builder()
-        .default_headers(h)
-        .build().unwrap();
-
-    let mut response: TMDBResponse;
-    loop {
-        if name_tokens.len() == 0 {
-            error!("Could not find title on TMDB!");
+    let found = match tmdb::search_release::<TMDBEntry>(&cfg, Kind::Tv, &release) {
+        Ok(found) => found,
+        Err(e) => {
+            error!("Searching TMDB for {:#?} failed: {}", release.title, e);
+            if cfg.non_interactive {
+                review::add(&cfg, &folder_name, &format!("Searching TMDB failed: {}", e), Vec::new());
+            }
             return None;
         }
+    };
+    if found.is_none() {
+        error!("Could not find title on TMDB!");
+    }
 
-        let name = name_tokens.join(" ");
-        trace!("Searching on TMDB for {:#?}", name);
-
-        let http_response = client
-            .get(format!("https://api.themoviedb.org/3/search/tv?query={}&include_adult=false&language=en-US&page=1", encode(name.as_str()).into_owned()))
-            .timeout(Duration::from_secs(120))
-            .send();
-
-        if http_response.is_err() {
-            warn!("Request error: {:#?}", http_response.unwrap_err());
-            return None;
-        }
+    if cfg.non_interactive {
+        let options = found.map(|f| f.search.results).unwrap_or_default();
+        let title_tokens: Vec<String> = release.title.split_whitespace().map(String::from).collect();
+        let scored = options.into_iter().map(|o| (media::match_score(&title_tokens, release.year, &o.name, o.first_air_date.as_deref()), o)).collect();
+        return media::auto_select(&folder_name, scored, &cfg);
+    }
 
-        response = http_response.unwrap().json::<TMDBResponse>().unwrap();
-        trace!("TMDB Reponse: {:#?}", response);
+    let choice = media::select(format!("Select show that resides in folder {style_bold}{}{style_reset}:", folder_name.display()).as_str(), Kind::Tv, found, &cfg)?;
+    debug!("Selected: {:#?}", choice);
+    decisions::record(&cfg, &folder_name, |d| d.tmdb_id = Some(choice.id));
+    Some(choice)
+}
 
-        if response.total_results == 0 {
-            name_tokens.pop();
-        } else {
-            break;
-        }
+// Details of an episode, only looked up if a naming template or NFO files need them
+fn episode_details(meta: &mut TMDBEntry, season: i32, episode: i32, cfg: &Config) -> Option<tmdb::Episode> {
+    if !cfg.nfo && !cfg.templates.episode.uses("episode_title") && !cfg.templates.episode_subtitle.uses("episode_title") {
+        return None;
     }
+    meta.episodes.entry((season, episode)).or_insert_with(|| tmdb::episode(cfg, meta.id, season, episode)).clone()
+}
 
-    let options = response.results;
-
-    let ans = Select::new(format!("Select show that resides in folder {style_bold}{}{style_reset} (Ctrl-C to skip):", folder_name.display()).as_str(), options).prompt();
-    match ans {
-        Ok(choice) => {
-            debug!("Selected: {:#?}", choice);
-            return Some(choice);
-        },
-        Err(e) => {
-            error!("Error while selecting content: {:#?}", e);
-            return None;
-        },
+// Fields for naming an episode or its subtitles
+fn fields(meta: &mut TMDBEntry, file: &Path, season: i32, episode: i32, cfg: &Config) -> Fields {
+    let episode_title = episode_details(meta, season, episode, cfg).and_then(|e| e.name);
+    Fields {
+        title: Some(meta.name.clone()),
+        year: media::release_year(meta.first_air_date.as_deref()),
+        tmdb_id: Some(meta.id),
+        imdb_id: meta.imdb_id.clone(),
+        season: Some(season),
+        episode: Some(episode),
+        episode_title,
+        resolution: media::resolution(file.file_name().unwrap_or_default().to_str().unwrap_or_default()),
+        ext: file.extension().map(|e| e.to_str().unwrap_or_default().to_string()),
+        ..Fields::default()
     }
 }
 
@@ -112,8 +123,11 @@ pub fn handle_show_files_and_folders(directory: PathBuf, files: Vec<DirEntry>, f
     
     // Check current directory for possible name
     primary_media = check_show_name(directory, cfg.clone());
-    match primary_media {
-        Some(_) => {
+    match primary_media.as_mut() {
+        Some(meta) => {
+            if cfg.nfo || cfg.templates.episode.uses("imdb_id") || cfg.templates.episode_subtitle.uses("imdb_id") {
+                meta.imdb_id = tmdb::imdb_id(&cfg, Kind::Tv, meta.id);
+            }
             // There is already primary media, check files and directories for more media for same show
             for file in files {
                 if file.file_type().unwrap().is_file() {
@@ -152,110 +166,92 @@ pub fn handle_show_files_and_folders(directory: PathBuf, files: Vec<DirEntry>, f
     moves
 }
 
+// Check if a sidecar file is going to be written along with one of the moves already
+fn planned(moves: &[Move], path: &Path) -> bool {
+    moves.iter().any(|m| m.sidecars.iter().any(|s| s.path == path))
+}
+
+// Season and episode of a file (e.g. "S01E02", "1x02" or absolute numbers like "Show - 02"), the season coming from the folder it is in
+// (e.g. "Season 01") if the name has none, or being the first for absolute numbers
+fn season_episode(file: &Path) -> Option<(i32, i32)> {
+    let release = release::parse(file.file_name()?.to_str()?);
+    let episode = release.episode?;
+    let folder = || file.parent()?.file_name()?.to_str().and_then(|f| release::parse(f).season);
+    Some((release.season.or_else(folder).unwrap_or(1), episode))
+}
+
 // Check files for episodes or subtitles, show required inquire dialoges
 fn check_show_file(file: PathBuf, primary_media: &mut Option<TMDBEntry>, cfg: &Config, moves: &mut Vec<Move>) {
     trace!("Checking {:#?}", file);
     match get_file_header(file.clone()) {
         Ok(header) => {
             // Try to parse Season/Episode from filename
-            let re = RegexBuilder::new(r"(?:S(?<season0>[0-9]+)\.?E(?<episode0>[0-9]+)|(?<season1>[0-9]+)x(?<episode1>[0-9]+))")
-                .case_insensitive(true).build().unwrap();
-            let Some(caps) = re.captures(file.to_str().unwrap_or_default()) else { warn!("Regex doesn't match {:#?}, skipping", file); return; };
-            let season: i32 = caps.name("season0").map_or_else(||caps.name("season1").map_or("", |m| m.as_str()), |m| m.as_str()).parse().unwrap();
-            let episode: i32 = caps.name("episode0").map_or_else(||caps.name("episode1").map_or("", |m| m.as_str()), |m| m.as_str()).parse().unwrap();
+            let Some((season, episode)) = season_episode(&file) else { warn!("No episode number in {:#?}, skipping", file); return; };
             trace!("Found Season {0:02}, Episode {1:02}", season, episode);
 
             // Handle video files
             if infer::is_video(&header) {
-                match primary_media.as_ref() {
+                match primary_media.as_mut() {
                     None => {
                         error!("Can not parse files without matched show!");
-                        return;
                     },
                     Some(primary_media) => {
-                        let original_path = file;
-                        let ext = original_path.extension().unwrap_or_default();
-                        let year: String;
-                        match primary_media.clone().first_air_date.unwrap_or_default().split('-').nth(0) {
-                            Some(y) => year = format!("({}) ", y),
-                            None => year = "".to_string()
-                        }
-                        let new_path = cfg.plex_library.join(format!("TV Shows/{0} {3}{{tmdb-{1}}}/Season {4:02}/{0} - S{4:02}E{5:02}.{2}", sanitise(primary_media.name.as_str()), primary_media.id, ext.to_str().unwrap_or_default(), year, season, episode));
-                        moves.push(Move { from: original_path, to: new_path });
-                    }
-                }
-            } else {
-                match file.extension() {
-                    Some(ext) => {
-                        if ext.eq_ignore_ascii_case("srt") ||
-                            ext.eq_ignore_ascii_case("ass") ||
-                            ext.eq_ignore_ascii_case("ssa") ||
-                            ext.eq_ignore_ascii_case("smi") ||
-                            ext.eq_ignore_ascii_case("pgs") ||
-                            ext.eq_ignore_ascii_case("vob") {
-                            // Subtitle file
-                            if primary_media.is_none() {
-                                warn!("Can not categorize subtitle file without primary media, skipping.");
-                                return;
-                            }
-
-                            let lang_code = Text::new(format!("Specify ISO-639-1 (2-letter) language code (e.g. 'en', 'de') or leave empty to discard for {style_bold}{}{style_reset}:", file.display()).as_str()).prompt();
-                            match lang_code {
-                                Ok(lang_code) => {
-                                    if lang_code == "" {
-                                        return;
-                                    }
-                                    let forced = Confirm::new("Is this a forced sub?").with_default(false).prompt();
-                                    match forced {
-                                        Ok(true) => {
-                                            // Forced
-                                            let original_path = file;
-                                            let ext = original_path.extension().unwrap_or_default();
-                                            let year: String;
-                                            match primary_media.clone().unwrap().first_air_date.unwrap_or_default().split('-').nth(0) {
-                                                Some(y) => year = format!("({}) ", y),
-                                                None => year = "".to_string()
-                                            }
-                                            let new_path = cfg.plex_library.join(format!("TV Shows/{0} {4}{{tmdb-{1}}}/Season {5:02}/{0} - S{5:02}E{6:02}.{3}.forced.{2}", sanitise(primary_media.as_ref().unwrap().name.as_str()), primary_media.as_ref().unwrap().id, ext.to_str().unwrap_or_default(), lang_code.to_ascii_lowercase(), year, season, episode));
-                                            moves.push(Move { from: original_path, to: new_path });
-                                            return;
-                                        },
-                                        Ok(false) => {
-                                            // Non-forced
-                                            let original_path = file;
-                                            let ext = original_path.extension().unwrap_or_default();
-                                            let year: String;
-                                            match primary_media.clone().unwrap().first_air_date.unwrap_or_default().split('-').nth(0) {
-                                                Some(y) => year = format!("({}) ", y),
-                                                None => year = "".to_string()
-                                            }
-                                            let new_path = cfg.plex_library.join(format!("TV Shows/{0} {4}{{tmdb-{1}}}/Season {5:02}/{0} - S{5:02}E{6:02}.{3}.{2}", sanitise(primary_media.as_ref().unwrap().name.as_str()), primary_media.as_ref().unwrap().id, ext.to_str().unwrap_or_default(), lang_code.to_ascii_lowercase(), year, season, episode));
-                                            moves.push(Move { from: original_path, to: new_path });
-                                            return;
-                                        },
-                                        Err(e) => {
-                                            error!("There was an error: {:#?}", e);
-                                            return;
-                                        },
-                                    }
-                                },
-                                Err(e) => {
-                                    error!("There was an error: {:#?}", e);
-                                    return;
-                                },
-                            }
-                        } else {
-                            info!("Not a video file nor subtitle, skipping");
+                        let fields = fields(primary_media, &file, season, episode, cfg);
+                        let Some(to) = cfg.templates.episode.path(&fields, cfg) else {
+                            error!("Can not name {:#?} with template {:#?}, a placeholder has no value", file, cfg.templates.episode.to_string());
                             return;
+                        };
+
+                        // Files of the show and its seasons go along with the first episode they are needed for, or the next one if it gets dropped
+                        let dir = naming::item_dir(&to, &fields, cfg);
+                        let mut sidecars = Vec::new();
+                        if cfg.nfo {
+                            sidecars.push(nfo::tvshow(&to, &fields, primary_media.overview.as_deref(), cfg));
                         }
-                    },
-                    None => {
-                        error!("File {:#?} has no file extension", file);
-                        return;
+                        if primary_media.artwork.insert(None) {
+                            sidecars.append(&mut artwork::item(Kind::Tv, primary_media.id, &dir, cfg));
+                        }
+                        if primary_media.artwork.insert(Some(season)) {
+                            sidecars.append(&mut artwork::season(primary_media.id, season, &dir, cfg));
+                        }
+                        sidecars.retain(|s| !planned(moves, &s.path));
+                        if cfg.nfo {
+                            let details = episode_details(primary_media, season, episode, cfg).unwrap_or_default();
+                            sidecars.push(nfo::episode(&to, &fields, details.id, details.overview.as_deref(), details.air_date.as_deref()));
+                        }
+                        moves.push(Move { from: file, to, sidecars });
                     }
                 }
+            } else if media::is_subtitle(&file) {
+                // Subtitle file
+                let Some(primary_media) = primary_media.as_mut() else {
+                    warn!("Can not categorize subtitle file without primary media, skipping.");
+                    return;
+                };
+
+                let Some((lang_code, forced)) = media::subtitle_language(&file, cfg) else { return; };
+                let fields = Fields { lang: Some(lang_code), forced, ..fields(primary_media, &file, season, episode, cfg) };
+                naming::push(moves, file.clone(), &cfg.templates.episode_subtitle, &fields, cfg);
+            } else if file.extension().is_none() {
+                error!("File {:#?} has no file extension", file);
+            } else {
+                info!("Not a video file nor subtitle, skipping");
             }
         },
         Err(error) => error!("Can not get file header for {:#?}, Error: {:#?}", file, error),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn episodes() {
+        assert_eq!(season_episode(Path::new("/in/Show/Season 02/Show.S02E05.720p.mkv")), Some((2, 5)));
+        assert_eq!(season_episode(Path::new("/in/Show/Show 3x07.mkv")), Some((3, 7)));
+        assert_eq!(season_episode(Path::new("/in/Frieren/[SubsPlease] Frieren - 01 (1080p).mkv")), Some((1, 1)));
+        assert_eq!(season_episode(Path::new("/in/Frieren/Season 02/[SubsPlease] Frieren - 29 (1080p).mkv")), Some((2, 29)));
+        assert_eq!(season_episode(Path::new("/in/Show/Extras/Behind the Scenes.mkv")), None);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{fmt, fs, io, path::PathBuf, sync::{Mutex, OnceLock}, thread, time::{Duration, Instant}};
+
+use log::{warn, trace};
+use reqwest::{blocking::{Client, Response}, header::{ACCEPT, RETRY_AFTER}, StatusCode};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::Value;
+use urlencoding::encode;
+
+use crate::{cache, config::Config, fixtures::{self, Fixtures}, release::Release};
+
+// TMDB allows around 50 requests per second, stay well below that
+const MIN_INTERVAL: Duration = Duration::from_millis(25);
+// Retries for rate limited requests and server errors, waiting 1, 2 and 4 seconds unless TMDB says otherwise
+const RETRIES: u32 = 3;
+
+// Reasons a TMDB request can fail
+#[derive(Debug)]
+pub enum Error {
+    // API key missing or invalid (401)
+    Unauthorized,
+    // No such movie, show or episode (404)
+    NotFound,
+    // Still rate limited (429) after retrying
+    RateLimited,
+    // Server error (5xx) after retrying, or any other unexpected status
+    Status(StatusCode),
+    // TMDB could not be reached
+    Network(reqwest::Error),
+    // Response does not look like expected
+    Decode(serde_json::Error),
+    // Nothing recorded for the request in replay mode
+    NoFixture(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unauthorized => write!(f, "TMDB rejected the API key, check tmdb_key in the config"),
+            Error::NotFound => write!(f, "not found on TMDB"),
+            Error::RateLimited => write!(f, "TMDB is still rate limiting requests after {} retries", RETRIES),
+            Error::Status(status) => write!(f, "TMDB answered {}", status),
+            Error::Network(e) => write!(f, "TMDB can not be reached: {}", e),
+            Error::Decode(e) => write!(f, "unexpected response from TMDB: {}", e),
+            Error::NoFixture(path) => write!(f, "no fixture recorded at {:#?}", path),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    // Whether TMDB can not answer at the moment, so a stale cached response is better than nothing
+    fn transient(&self) -> bool {
+        matches!(self, Error::Network(_) | Error::RateLimited) || matches!(self, Error::Status(status) if status.is_server_error())
+    }
+}
+
+// Page of search results
+#[derive(Deserialize, Debug)]
+pub struct Search<T> {
+    pub results: Vec<T>,
+    pub total_results: i32,
+    #[serde(default)]
+    pub page: i32,
+    #[serde(default)]
+    pub total_pages: i32,
+}
+
+// Search that found something, with the query it was found with to fetch further pages
+#[derive(Debug)]
+pub struct Found<T> {
+    pub query: String,
+    pub year: Option<i32>,
+    pub search: Search<T>,
+}
+
+// External ids of a movie or show
+#[derive(Deserialize, Debug)]
+pub struct ExternalIds {
+    pub imdb_id: Option<String>,
+}
+
+// Single episode of a show
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Episode {
+    pub id: Option<i32>,
+    pub name: Option<String>,
+    pub overview: Option<String>,
+    pub air_date: Option<String>,
+}
+
+// Movie or show, TMDB has separate ids and endpoints for both
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Movie,
+    Tv,
+}
+
+impl Kind {
+    // First path segment of TMDB endpoints for the kind, e.g. "tv" in "tv/1396/external_ids"
+    pub fn endpoint(self) -> &'static str {
+        match self {
+            Kind::Movie => "movie",
+            Kind::Tv => "tv",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Movie => write!(f, "movie"),
+            Kind::Tv => write!(f, "show"),
+        }
+    }
+}
+
+// Search for movies or shows by name and, if known, release year (first air year for shows)
+pub fn search<T: DeserializeOwned>(cfg: &Config, kind: Kind, query: &str, year: Option<i32>, page: i32) -> Result<Search<T>, Error> {
+    let mut endpoint = format!("search/{}?query={}&include_adult=false&language=en-US&page={}", kind.endpoint(), encode(query), page);
+    if let Some(year) = year {
+        let param = match kind {
+            Kind::Movie => "primary_release_year",
+            Kind::Tv => "first_air_date_year",
+        };
+        endpoint.push_str(&format!("&{}={}", param, year));
+    }
+    fetch(cfg, &endpoint)
+}
+
+// Results of looking up an IMDb or TVDB id
+#[derive(Deserialize, Debug)]
+struct Find<T> {
+    #[serde(default = "Vec::new")]
+    movie_results: Vec<T>,
+    #[serde(default = "Vec::new")]
+    tv_results: Vec<T>,
+}
+
+// Movie or show by its TMDB id
+pub fn details<T: DeserializeOwned>(cfg: &Config, kind: Kind, id: i32) -> Option<T> {
+    get(cfg, &format!("{}/{}?language=en-US", kind.endpoint(), id))
+}
+
+// Movie or show by its id on another site, `source` being "imdb_id" or "tvdb_id"
+pub fn find<T: DeserializeOwned>(cfg: &Config, kind: Kind, id: &str, source: &str) -> Option<T> {
+    let found = get::<Find<T>>(cfg, &format!("find/{}?external_source={}&language=en-US", encode(id), source))?;
+    let results = match kind {
+        Kind::Movie => found.movie_results,
+        Kind::Tv => found.tv_results,
+    };
+    let result = results.into_iter().next();
+    if result.is_none() {
+        warn!("No {} found on TMDB for {} {}", kind, source, id);
+    }
+    result
+}
+
+// Movie or show a release is tagged with, by its TMDB id or else its IMDb or TVDB id
+pub fn tagged<T: DeserializeOwned>(cfg: &Config, kind: Kind, release: &Release) -> Option<T> {
+    if let Some(id) = release.tmdb_id {
+        return details(cfg, kind, id);
+    }
+    match (&release.imdb_id, release.tvdb_id) {
+        (Some(id), _) => find(cfg, kind, id, "imdb_id"),
+        (None, Some(id)) => find(cfg, kind, &id.to_string(), "tvdb_id"),
+        (None, None) => None,
+    }
+}
+
+// Search for a release, trying its queries in order until one finds something, None if none does
+pub fn search_release<T: DeserializeOwned + fmt::Debug>(cfg: &Config, kind: Kind, release: &Release) -> Result<Option<Found<T>>, Error> {
+    for (query, year) in release.queries() {
+        trace!("Searching on TMDB for {:#?} (year {:?})", query, year);
+        let response = search::<T>(cfg, kind, &query, year, 1)?;
+        trace!("TMDB Reponse: {:#?}", response);
+        if response.total_results > 0 {
+            return Ok(Some(Found { query, year, search: response }));
+        }
+    }
+    Ok(None)
+}
+
+// Fetch details from the TMDB API, e.g. "movie/27205/external_ids", None (with a warning) if the request fails
+pub fn get<T: DeserializeOwned>(cfg: &Config, endpoint: &str) -> Option<T> {
+    match fetch(cfg, endpoint) {
+        Ok(response) => Some(response),
+        Err(e) => {
+            warn!("TMDB request for {:#?} failed: {}", endpoint, e);
+            None
+        }
+    }
+}
+
+// Fetch and decode a response from the TMDB API
+// Responses are cached on disk, stale ones are still used if TMDB can not answer
+pub fn fetch<T: DeserializeOwned>(cfg: &Config, endpoint: &str) -> Result<T, Error> {
+    let cached = cache::get(cfg, endpoint);
+    if let Some(cached) = cached.as_ref().filter(|c| c.fresh(cfg)) {
+        if let Ok(response) = serde_json::from_value::<T>(cached.response.clone()) {
+            trace!("Using cached TMDB response for {:#?}", endpoint);
+            return Ok(response);
+        }
+    }
+
+    let response = request(cfg, endpoint).and_then(|value| serde_json::from_value::<T>(value.clone()).map(|r| (r, value)).map_err(Error::Decode));
+    match response {
+        Ok((response, value)) => {
+            cache::put(cfg, endpoint, &value);
+            Ok(response)
+        },
+        Err(e) if e.transient() => {
+            let Some(cached) = cached else { return Err(e); };
+            let Ok(response) = serde_json::from_value::<T>(cached.response.clone()) else { return Err(e); };
+            warn!("TMDB request for {:#?} failed, using response cached at {}: {}", endpoint, cached.time(), e);
+            Ok(response)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+// Client shared by all requests, so connections get reused
+fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| Client::builder().timeout(Duration::from_secs(120)).build().expect("Can not initialise HTTP client"))
+}
+
+// Earliest time the next request may be sent, shared by all threads
+static NEXT_REQUEST: Mutex<Option<Instant>> = Mutex::new(None);
+
+// Wait until the next request may be sent
+fn throttle() {
+    let wait = {
+        let mut next = NEXT_REQUEST.lock().unwrap();
+        let now = Instant::now();
+        let at = next.map_or(now, |n| n.max(now));
+        *next = Some(at + MIN_INTERVAL);
+        at - now
+    };
+    thread::sleep(wait);
+}
+
+// Hold off all requests for a while, e.g. when TMDB asks for it
+fn hold_off(delay: Duration) {
+    let mut next = NEXT_REQUEST.lock().unwrap();
+    let until = Instant::now() + delay;
+    *next = Some(next.map_or(until, |n| n.max(until)));
+}
+
+fn request(cfg: &Config, endpoint: &str) -> Result<Value, Error> {
+    if let Fixtures::Replay(dir) = &cfg.fixtures {
+        let fixture = fixtures::load(dir, endpoint).ok_or_else(|| Error::NoFixture(fixtures::path(dir, endpoint)))?;
+        let status = StatusCode::from_u16(fixture.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        return if status.is_success() { Ok(fixture.response) } else { Err(status_error(status)) };
+    }
+
+    let mut attempt = 0;
+    loop {
+        throttle();
+        trace!("Requesting {:#?} from TMDB", endpoint);
+        let response = client()
+            .get(format!("{}/{}", cfg.tmdb_url.trim_end_matches('/'), endpoint))
+            .bearer_auth(&cfg.tmdb_key)
+            .header(ACCEPT, "application/json")
+            .send()
+            .map_err(Error::Network)?;
+
+        let status = response.status();
+        if status.is_success() {
+            let body = response.text().map_err(Error::Network)?;
+            let value = serde_json::from_str(&body).map_err(Error::Decode)?;
+            if let Fixtures::Record(dir) = &cfg.fixtures {
+                fixtures::save(dir, endpoint, status.as_u16(), &value);
+            }
+            return Ok(value);
+        }
+        if (status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()) && attempt < RETRIES {
+            let delay = retry_after(&response).unwrap_or(Duration::from_secs(1 << attempt));
+            warn!("TMDB answered {} for {:#?}, retrying in {}s", status, endpoint, delay.as_secs());
+            hold_off(delay);
+            attempt += 1;
+            continue;
+        }
+        if let Fixtures::Record(dir) = &cfg.fixtures {
+            fixtures::save(dir, endpoint, status.as_u16(), &Value::Null);
+        }
+        return Err(status_error(status));
+    }
+}
+
+fn status_error(status: StatusCode) -> Error {
+    match status {
+        StatusCode::UNAUTHORIZED => Error::Unauthorized,
+        StatusCode::NOT_FOUND => Error::NotFound,
+        StatusCode::TOO_MANY_REQUESTS => Error::RateLimited,
+        _ => Error::Status(status),
+    }
+}
+
+// Delay asked for in the Retry-After header, in seconds (dates are not supported), at most a minute
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds: u64 = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds.min(60)))
+}
+
+// IMDb id of a movie or show
+pub fn imdb_id(cfg: &Config, kind: Kind, id: i32) -> Option<String> {
+    get::<ExternalIds>(cfg, &format!("{}/{}/external_ids", kind.endpoint(), id))?.imdb_id.filter(|i| !i.is_empty())
+}
+
+// Details of an episode of a show
+pub fn episode(cfg: &Config, id: i32, season: i32, episode: i32) -> Option<Episode> {
+    get::<Episode>(cfg, &format!("tv/{}/season/{}/episode/{}", id, season, episode))
+}
+
+// Download a file, e.g. an image from the TMDB image server, recorded and replayed along with the TMDB responses
+pub fn download(cfg: &Config, url: &str) -> io::Result<Vec<u8>> {
+    if let Fixtures::Replay(dir) = &cfg.fixtures {
+        let path = fixtures::download_path(dir, url);
+        trace!("Replaying {:#?} from {:#?}", url, path);
+        return fs::read(&path).map_err(|e| io::Error::new(e.kind(), format!("no fixture recorded at {:#?}", path)));
+    }
+
+    trace!("Downloading {:#?}", url);
+    let contents = client()
+        .get(url)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .and_then(|r| r.bytes())
+        .map(|b| b.to_vec())
+        .map_err(io::Error::other)?;
+    if let Fixtures::Record(dir) = &cfg.fixtures {
+        fixtures::save_download(dir, url, &contents);
+    }
+    Ok(contents)
+}
@@ -0,0 +1,542 @@
+use std::{fs::{self, File, Metadata, OpenOptions}, io::{self, Read, Write, Seek, SeekFrom}, path::{Component, Path, PathBuf}, cmp, sync::Mutex};
+
+use indicatif::{ProgressBar, HumanBytes};
+use log::{info, warn, error};
+use walkdir::WalkDir;
+use xxhash_rust::xxh3::Xxh3;
+use serde::{Serialize, Deserialize};
+use inline_colorization::*;
+
+use crate::{config::Config, conflict, media::{Move, Contents, timestamp}, tmdb, journal::{self, Entry, Outcome}, progress::Progress, scheduler, permissions};
+
+// How files get into the library
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    Copy,
+    Move,
+    Hardlink,
+    Reflink,
+    Symlink,
+    RelativeSymlink,
+}
+
+impl Mode {
+    // What is being done to files in this mode, for output
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Mode::Copy => "Copying",
+            Mode::Move => "Moving",
+            Mode::Hardlink => "Linking",
+            Mode::Reflink => "Cloning",
+            Mode::Symlink | Mode::RelativeSymlink => "Symlinking",
+        }
+    }
+}
+
+// What to do when files can not be linked (e.g. source and library are on different devices)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkFallback {
+    // Copy the file instead
+    #[default]
+    Copy,
+    // Leave the file out
+    Skip,
+    // Count the file as failed
+    Fail,
+}
+
+// Limit for the number of files transferred at the same time, for transfers from and/or to the devices the given paths are on
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransferLimit {
+    pub source: Option<PathBuf>,
+    pub destination: Option<PathBuf>,
+    pub workers: usize,
+}
+
+impl TransferLimit {
+    fn matches(&self, move_file: &Move) -> bool {
+        self.source.as_ref().is_none_or(|s| on_device_of(&move_file.from, s)) &&
+        self.destination.as_ref().is_none_or(|d| on_device_of(&move_file.to, d))
+    }
+}
+
+// Execute all moves (or copies) on parallel workers and record every one of them in the journal
+pub fn execute(moves: Vec<Move>, mode: Mode, dry_run: bool, cfg: &Config) {
+    if dry_run {
+        for move_file in moves {
+            println!("{} {style_bold}{color_red}{}{color_reset}{style_reset} -> {style_bold}{color_green}{}{color_reset}{style_reset}", mode.verb(), move_file.from.display(), move_file.to.display());
+            for sidecar in move_file.sidecars {
+                let verb = match sidecar.contents {
+                    Contents::Text(_) => "Writing",
+                    Contents::Download(_) => "Downloading",
+                };
+                println!("{} {style_bold}{color_green}{}{color_reset}{style_reset}", verb, sidecar.path.display());
+            }
+        }
+        return;
+    }
+
+    report_partial_copies(&moves);
+
+    let run = journal::new_run();
+    let sizes: Vec<u64> = moves.iter().map(|m| fs::metadata(&m.from).map(|m| m.len()).unwrap_or_default()).collect();
+    let progress = Progress::new(sizes.iter().sum(), cfg.progress);
+    let journal_lock = Mutex::new(());
+    let created_dirs = Mutex::new(Vec::new());
+
+    // Every transfer falls under the first limit matching it, if any
+    let limits: Vec<usize> = cfg.transfer_limits.iter().map(|l| l.workers).collect();
+    let jobs: Vec<(Option<usize>, (Move, u64))> = moves.into_iter().zip(sizes)
+        .map(|(m, size)| (cfg.transfer_limits.iter().position(|l| l.matches(&m)), (m, size)))
+        .collect();
+
+    let results = scheduler::run(jobs, &limits, cfg.transfer_workers, |(move_file, size)| {
+        progress.println(format!("{} {style_bold}{color_red}{}{color_reset}{style_reset} -> {style_bold}{color_green}{}{color_reset}{style_reset}", mode.verb(), move_file.from.display(), move_file.to.display()));
+
+        let (mode, outcome, backup) = match link_mode(&move_file, mode, cfg.link_fallback) {
+            Err(outcome) => (mode, outcome, None),
+            Ok(mode) => match transfer(&move_file, mode, run, cfg, &progress, &created_dirs) {
+                Ok((mode, backup)) => (mode, Outcome::Done, backup),
+                Err(outcome) => {
+                    if let Outcome::Failed(e) = &outcome {
+                        error!("Transfer of {:#?} failed with error {:#?}", move_file.from, e);
+                    }
+                    (mode, outcome, None)
+                }
+            }
+        };
+        progress.add_done(size);
+        let sidecars = write_sidecars(&move_file, outcome == Outcome::Done, cfg);
+
+        let entry = Entry { run, timestamp: timestamp(), mode, from: move_file.from, to: move_file.to, size, outcome, backup, sidecars };
+        let _lock = journal_lock.lock().unwrap();
+        journal::record(cfg, &entry);
+        entry
+    });
+
+    permissions::apply_dir_times(&created_dirs.into_inner().unwrap(), cfg);
+
+    let done = results.iter().filter(|e| e.outcome == Outcome::Done).count();
+    let bytes = results.iter().filter(|e| e.outcome == Outcome::Done).map(|e| e.size).sum();
+    let failed: Vec<&Entry> = results.iter().filter(|e| matches!(e.outcome, Outcome::Failed(_))).collect();
+    progress.finish(done, failed.len(), results.len() - done - failed.len(), bytes);
+    for entry in failed {
+        if let Outcome::Failed(e) = &entry.outcome {
+            println!("{style_bold}{color_red}Failed{color_reset}{style_reset} {}: {}", entry.from.display(), e);
+        }
+    }
+}
+
+// Write the files going along with a transferred one, leaving existing ones alone, and return the ones written
+// Files of the movie, show or season are written even if the file could not be transferred, as long as their folder is there
+fn write_sidecars(move_file: &Move, done: bool, cfg: &Config) -> Vec<PathBuf> {
+    let mut written = Vec::new();
+    for sidecar in move_file.sidecars.iter().filter(|s| done || (s.item && s.path.parent().is_some_and(Path::is_dir))) {
+        if fs::symlink_metadata(&sidecar.path).is_ok() {
+            info!("{:#?} exists already, leaving it alone", sidecar.path);
+            continue;
+        }
+        let contents = match &sidecar.contents {
+            Contents::Text(text) => Ok(text.as_bytes().to_vec()),
+            Contents::Download(url) => tmdb::download(cfg, url),
+        };
+        let result = contents.and_then(|contents| {
+            let mut f = OpenOptions::new().write(true).create_new(true).open(&sidecar.path)?;
+            f.write_all(&contents).inspect_err(|_| _ = fs::remove_file(&sidecar.path))
+        });
+        match result {
+            Ok(_) => {
+                permissions::apply_created(&sidecar.path, cfg);
+                written.push(sidecar.path.clone());
+            },
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => info!("{:#?} exists already, leaving it alone", sidecar.path),
+            Err(e) => warn!("Can not write {:#?}: {:#?}", sidecar.path, e),
+        }
+    }
+    written
+}
+
+// Check if files can be linked into the library, and apply the fallback if source and destination are on different devices
+fn link_mode(move_file: &Move, mode: Mode, fallback: LinkFallback) -> Result<Mode, Outcome> {
+    if mode != Mode::Hardlink && mode != Mode::Reflink {
+        return Ok(mode);
+    }
+    if same_device(&move_file.from, &move_file.to) != Some(false) {
+        return Ok(mode);
+    }
+
+    match fallback {
+        LinkFallback::Copy => {
+            warn!("{:#?} is on a different device than the library, copying instead", move_file.from);
+            Ok(Mode::Copy)
+        },
+        LinkFallback::Skip => {
+            warn!("{:#?} is on a different device than the library, skipping", move_file.from);
+            Err(Outcome::Skipped("different device".to_string()))
+        },
+        LinkFallback::Fail => {
+            error!("{:#?} is on a different device than the library", move_file.from);
+            Err(Outcome::Failed("different device".to_string()))
+        },
+    }
+}
+
+// Device a path is on, or would be on if it does not exist yet (looking at the closest existing directory)
+#[cfg(unix)]
+pub fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    path.ancestors().find_map(|a| fs::metadata(a).ok()).map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+pub fn device(_path: &Path) -> Option<u64> {
+    None
+}
+
+// Check if a file and the destination are on the same device, None if that can not be told
+pub fn same_device(from: &Path, to: &Path) -> Option<bool> {
+    Some(device(from)? == device(to)?)
+}
+
+// Check if a path is on the same device as another one, comparing paths if devices can not be told
+fn on_device_of(path: &Path, other: &Path) -> bool {
+    match (device(path), device(other)) {
+        (Some(a), Some(b)) => a == b,
+        _ => path.starts_with(other),
+    }
+}
+
+// Transfer a single file, returning the mode actually used and where an overwritten destination file has been kept, or why it was not transferred
+// Directories created for it are added to `created_dirs` along with the metadata of the source directory
+fn transfer(move_file: &Move, mode: Mode, run: u64, cfg: &Config, progress: &Progress, created_dirs: &Mutex<Vec<(PathBuf, Metadata)>>) -> Result<(Mode, Option<PathBuf>), Outcome> {
+    let source = fs::metadata(&move_file.from)?;
+    if let Some(parent) = move_file.to.parent() {
+        let source_dir = move_file.from.parent().unwrap_or(Path::new("."));
+        let source_dir_meta = fs::metadata(source_dir);
+        let created = permissions::create_dirs(parent, source_dir, cfg)?;
+        if let Ok(meta) = source_dir_meta {
+            created_dirs.lock().unwrap().extend(created.into_iter().map(|d| (d, meta.clone())));
+        }
+    }
+
+    // Links pointing nowhere are of no use to anyone, just replace them
+    if is_dangling(&move_file.to) {
+        warn!("Replacing dangling link {:#?}", move_file.to);
+        fs::remove_file(&move_file.to)?;
+    }
+
+    // Keep whatever is at the destination, so the run can be undone, unless that is the source renamed in case only on a case-insensitive file system
+    let backup = if fs::symlink_metadata(&move_file.to).is_ok() && !conflict::is_source(&move_file.to, &move_file.from) {
+        let backup = backup_path(&move_file.to, run);
+        warn!("Overwriting {:#?}, keeping the old file as {:#?}", move_file.to, backup);
+        fs::rename(&move_file.to, &backup)?;
+        Some(backup)
+    } else {
+        None
+    };
+
+    let result = match mode {
+        Mode::Move => move_file_or_copy(&move_file.from, &move_file.to, cfg.verify, progress).map(|_| mode).map_err(Outcome::from),
+        Mode::Copy => copy_file(&move_file.from, &move_file.to, cfg.verify, progress).map(|_| mode).map_err(Outcome::from),
+        Mode::Hardlink => fs::hard_link(&move_file.from, &move_file.to).map(|_| mode).or_else(|e| link_failed(e, move_file, cfg, progress)),
+        Mode::Reflink => reflink_copy::reflink(&move_file.from, &move_file.to).map(|_| mode).or_else(|e| link_failed(e, move_file, cfg, progress)),
+        Mode::Symlink | Mode::RelativeSymlink => create_symlink(&move_file.from, &move_file.to, mode == Mode::RelativeSymlink).map(|_| mode).map_err(Outcome::from),
+    };
+
+    match result {
+        Ok(mode) => {
+            permissions::apply_file(&move_file.to, &source, mode, cfg);
+            Ok((mode, backup))
+        },
+        Err(e) => {
+            if let Some(backup) = backup {
+                _ = fs::rename(backup, &move_file.to);
+            }
+            Err(e)
+        }
+    }
+}
+
+// Apply the link fallback if linking failed for other reasons than different devices (e.g. no reflink support on the file system)
+fn link_failed(e: io::Error, move_file: &Move, cfg: &Config, progress: &Progress) -> Result<Mode, Outcome> {
+    match cfg.link_fallback {
+        LinkFallback::Copy => {
+            warn!("Can not link, error {:#?}, copying instead", e);
+            Ok(copy_file(&move_file.from, &move_file.to, cfg.verify, progress).map(|_| Mode::Copy)?)
+        },
+        LinkFallback::Skip => {
+            warn!("Can not link {:#?}, error {:#?}, skipping", move_file.from, e);
+            Err(Outcome::Skipped(e.to_string()))
+        },
+        LinkFallback::Fail => Err(Outcome::from(e)),
+    }
+}
+
+// Create a symlink at `to` pointing back at `from`, either absolute or relative to the directory of the link
+fn create_symlink(from: &Path, to: &Path, relative: bool) -> io::Result<()> {
+    let from = fs::canonicalize(from)?;
+    let target = if relative {
+        let dir = fs::canonicalize(to.parent().unwrap_or(Path::new(".")))?;
+        relative_path(&dir, &from)
+    } else {
+        from
+    };
+
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, to);
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_file(target, to);
+}
+
+// Path of `to` relative to the directory `dir`, both have to be absolute
+fn relative_path(dir: &Path, to: &Path) -> PathBuf {
+    let dir: Vec<Component> = dir.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = dir.iter().zip(to.iter()).take_while(|(a, b)| a == b).count();
+
+    let mut path = PathBuf::new();
+    for _ in common..dir.len() {
+        path.push("..");
+    }
+    for c in &to[common..] {
+        path.push(c);
+    }
+    path
+}
+
+// Check if a path is a symlink whose target does not exist (anymore)
+pub fn is_dangling(path: &Path) -> bool {
+    fs::symlink_metadata(path).map(|m| m.file_type().is_symlink()).unwrap_or(false) && fs::metadata(path).is_err()
+}
+
+// Find all dangling symlinks in a directory tree, e.g. left behind by deleted or moved downloads
+pub fn dangling_links(root: &Path) -> Vec<PathBuf> {
+    WalkDir::new(root)
+        .into_iter()
+        .flatten()
+        .filter(|e| e.path_is_symlink() && is_dangling(e.path()))
+        .map(|e| e.into_path())
+        .collect()
+}
+
+// Hidden name next to the destination an overwritten file gets moved to
+fn backup_path(to: &Path, run: u64) -> PathBuf {
+    let name = to.file_name().unwrap_or_default().to_str().unwrap_or_default();
+    to.with_file_name(format!(".{}.{}.bak", name, run))
+}
+
+// Move a file, falling back to copying and deleting if renaming is not possible (e.g. across file systems)
+pub fn move_file_or_copy(from: &Path, to: &Path, verify: bool, progress: &Progress) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            warn!("Can not rename, error {:#?}, copying and deleting instead", e);
+            // The source only gets deleted if the copy is complete (and verified)
+            copy_file(from, to, verify, progress)?;
+            if let Err(e) = fs::remove_file(from) {
+                warn!("Copied, but can not delete {:#?}: {:#?}", from, e);
+            }
+            Ok(())
+        }
+    }
+}
+
+// Copy a file in chunks, showing progress, and optionally verify the copy by comparing checksums of source and destination
+// The copy is written to a hidden file next to the destination and only renamed into place when complete, so media servers never see half a file
+pub fn copy_file(from: &Path, to: &Path, verify: bool, progress: &Progress) -> io::Result<()> {
+    let size = fs::metadata(from)?.len();
+    let part = part_path(to);
+    let bar = progress.file(from, size);
+    let copied = copy_chunked(from, &part, verify, &bar, progress);
+    // Everything the bar shows until now has been accounted for in the overall progress as well
+    let counted = bar.position();
+    let result = copied.and_then(|source| {
+        if let Some(source) = source {
+            bar.set_prefix("Verifying");
+            bar.set_position(0);
+            let destination = checksum(&part, &bar)?;
+            if source != destination {
+                error!("Checksum mismatch for {:#?} ({:032x}) and {:#?} ({:032x}), removing the copy", from, source, part, destination);
+                _ = fs::remove_file(&part);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("checksum mismatch after copying {} bytes", size)));
+            }
+        }
+        fs::rename(&part, to)
+    });
+    progress.remove(&bar, counted);
+
+    if result.is_err() && part.exists() {
+        warn!("Keeping partial copy {:#?}, it gets resumed on the next run", part);
+    }
+    result
+}
+
+// Hidden name next to the destination a file gets copied to before being renamed into place
+pub fn part_path(to: &Path) -> PathBuf {
+    let name = to.file_name().unwrap_or_default().to_str().unwrap_or_default();
+    to.with_file_name(format!(".{}.part", name))
+}
+
+// Report partial copies left behind by interrupted runs in the directories files get transferred to
+fn report_partial_copies(moves: &[Move]) {
+    let mut dirs: Vec<&Path> = moves.iter().filter_map(|m| m.to.parent()).collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else { continue; };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with('.') || !name.ends_with(".part") {
+                continue;
+            }
+            if moves.iter().any(|m| part_path(&m.to) == entry.path()) {
+                info!("Found partial copy {:#?} of an interrupted run, resuming it", entry.path());
+            } else {
+                warn!("Found partial copy {:#?} of an interrupted run that is not part of this run, remove it if it is not needed anymore", entry.path());
+            }
+        }
+    }
+}
+
+// Size of the chunks files get copied and hashed in
+const CHUNK_SIZE: usize = 4 << 20;
+
+// Copy a file chunk by chunk, resuming a partial copy of an interrupted run, returning the checksum of the source if the copy gets verified
+fn copy_chunked(from: &Path, part: &Path, verify: bool, bar: &ProgressBar, progress: &Progress) -> io::Result<Option<u128>> {
+    let mut source = File::open(from)?;
+    let mut hasher = verify.then(Xxh3::new);
+    let mut buf = vec![0; CHUNK_SIZE];
+
+    let resume_at = resumable(&mut source, part, &mut hasher)?;
+    let mut destination = if resume_at > 0 {
+        info!("Resuming copy of {:#?} at {}", from, HumanBytes(resume_at));
+        bar.inc(resume_at);
+        progress.inc(resume_at);
+        OpenOptions::new().append(true).open(part)?
+    } else {
+        File::create(part)?
+    };
+
+    loop {
+        let n = source.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        destination.write_all(&buf[..n])?;
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&buf[..n]);
+        }
+        bar.inc(n as u64);
+        progress.inc(n as u64);
+    }
+    destination.sync_all()?;
+    fs::set_permissions(part, source.metadata()?.permissions())?;
+    Ok(hasher.map(|h| h.digest128()))
+}
+
+// Find out how much of a partial copy of an interrupted run can be kept, all of it has to be the same as the start of the source
+// The source is left where copying continues, with the part that has been kept added to the checksum
+fn resumable(source: &mut File, part: &Path, hasher: &mut Option<Xxh3>) -> io::Result<u64> {
+    let Ok(meta) = fs::metadata(part) else { return Ok(0); };
+    let len = meta.len();
+    if len == 0 || len > source.metadata()?.len() {
+        return Ok(0);
+    }
+
+    let mut f = File::open(part)?;
+    let mut copied = vec![0; CHUNK_SIZE];
+    let mut original = vec![0; CHUNK_SIZE];
+    let mut compared = 0;
+    while compared < len {
+        let n = cmp::min(len - compared, CHUNK_SIZE as u64) as usize;
+        f.read_exact(&mut copied[..n])?;
+        source.read_exact(&mut original[..n])?;
+        if copied[..n] != original[..n] {
+            warn!("Partial copy {:#?} does not match its source, starting over", part);
+            source.seek(SeekFrom::Start(0))?;
+            if let Some(hasher) = hasher {
+                hasher.reset();
+            }
+            return Ok(0);
+        }
+        if let Some(hasher) = hasher {
+            hasher.update(&original[..n]);
+        }
+        compared += n as u64;
+    }
+    Ok(len)
+}
+
+// XXH3 checksum of a file, read in chunks to keep memory usage low for big files
+fn checksum(path: &Path, bar: &ProgressBar) -> io::Result<u128> {
+    let mut f = File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        bar.inc(n as u64);
+    }
+    Ok(hasher.digest128())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn link_fallbacks() {
+        let mut cfg = config::scratch("link-fallback");
+        let from = cfg.data_dir.join("Movie.mkv");
+        fs::write(&from, "movie").unwrap();
+        let move_file = Move { from, to: cfg.plex_library.join("Movie.mkv"), sidecars: Vec::new() };
+        let progress = Progress::new(0, false);
+        let unsupported = || io::Error::new(io::ErrorKind::Unsupported, "no reflinks");
+
+        cfg.link_fallback = LinkFallback::Skip;
+        assert!(matches!(link_failed(unsupported(), &move_file, &cfg, &progress), Err(Outcome::Skipped(_))));
+        cfg.link_fallback = LinkFallback::Fail;
+        assert!(matches!(link_failed(unsupported(), &move_file, &cfg, &progress), Err(Outcome::Failed(_))));
+        assert!(!move_file.to.exists());
+        cfg.link_fallback = LinkFallback::Copy;
+        assert_eq!(link_failed(unsupported(), &move_file, &cfg, &progress), Ok(Mode::Copy));
+        assert_eq!(fs::read_to_string(&move_file.to).unwrap(), "movie");
+    }
+
+    #[test]
+    fn resume_partial_copies() {
+        let cfg = config::scratch("resume");
+        let source: Vec<u8> = (0..2 * CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let from = cfg.data_dir.join("Movie.mkv");
+        fs::write(&from, &source).unwrap();
+        let to = cfg.plex_library.join("Movie.mkv");
+        let progress = Progress::new(0, false);
+
+        // Only the start of the partial copy is broken, its end matches the source
+        let mut part = source[..CHUNK_SIZE + 50].to_vec();
+        part[10] ^= 0xff;
+        fs::write(part_path(&to), &part).unwrap();
+        let mut hasher = Some(Xxh3::new());
+        assert_eq!(resumable(&mut File::open(&from).unwrap(), &part_path(&to), &mut hasher).unwrap(), 0);
+        copy_file(&from, &to, false, &progress).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), source);
+
+        // An intact partial copy is kept and still counts towards the checksum
+        fs::remove_file(&to).unwrap();
+        fs::write(part_path(&to), &source[..CHUNK_SIZE + 50]).unwrap();
+        let mut file = File::open(&from).unwrap();
+        let mut hasher = Some(Xxh3::new());
+        assert_eq!(resumable(&mut file, &part_path(&to), &mut hasher).unwrap(), (CHUNK_SIZE + 50) as u64);
+        assert_eq!(file.stream_position().unwrap(), (CHUNK_SIZE + 50) as u64);
+        assert_eq!(hasher.unwrap().digest128(), xxhash_rust::xxh3::xxh3_128(&source[..CHUNK_SIZE + 50]));
+        copy_file(&from, &to, true, &progress).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), source);
+    }
+}